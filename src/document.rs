@@ -1,10 +1,18 @@
-/// A Document contains an id and content.
+/// A Document contains an id and content, and optionally a timestamp and metadata.
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Hash, RustcEncodable, RustcDecodable)]
 pub struct Document {
     /// The id of the document
     pub id: usize,
     /// The document's content
     pub content: String,
+    /// The document's timestamp, e.g. when it was written, in caller-defined units such as
+    /// seconds since the Unix epoch
+    pub timestamp: Option<u64>,
+    /// The document's metadata, e.g. its title or url, which is stored but not indexed
+    pub metadata: Option<String>,
+    // For documents constructed by `multi_valued`, the byte offsets in the content at which each
+    // value but the first starts, each just after the newline separating it from the previous one
+    value_starts: Vec<usize>,
 }
 
 impl Document {
@@ -16,9 +24,45 @@ impl Document {
         Document {
             id: id,
             content: content.into(),
+            timestamp: None,
+            metadata: None,
+            value_starts: vec![],
         }
     }
 
+    /// Construct a new Document made up of several values, e.g. the tags of an item. The values
+    /// are indexed independently of each other: the document's content is the values separated by
+    /// newlines, but phrase and proximity queries don't match across values.
+    pub fn multi_valued<I>(id: usize, values: I) -> Document
+        where I: IntoIterator,
+              I::Item: AsRef<str>
+    {
+        let mut content = String::new();
+        let mut value_starts = vec![];
+        for (i, value) in values.into_iter().enumerate() {
+            if i > 0 {
+                content.push('\n');
+                value_starts.push(content.len());
+            }
+            content.push_str(value.as_ref());
+        }
+        Document { value_starts: value_starts, ..Document::new(id, content) }
+    }
+
+    /// Returns the document with its timestamp set to the given timestamp.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Document {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Returns the document with its metadata set to the given metadata.
+    pub fn with_metadata<T>(mut self, metadata: T) -> Document
+        where T: Into<String>
+    {
+        self.metadata = Some(metadata.into());
+        self
+    }
+
     /// Returns a reference to the document's id
     pub fn id(&self) -> usize {
         self.id
@@ -28,4 +72,41 @@ impl Document {
     pub fn content(&self) -> &str {
         &self.content
     }
+
+    /// Returns the document's timestamp, if it has one
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Returns the values the document is made up of: its content, unless it was constructed by
+    /// `multi_valued`.
+    pub fn values(&self) -> Vec<&str> {
+        let mut values = Vec::with_capacity(self.value_starts.len() + 1);
+        let mut start = 0;
+        for &next_start in &self.value_starts {
+            // Each value but the last is followed by a newline separator
+            values.push(&self.content[start..next_start - 1]);
+            start = next_start;
+        }
+        values.push(&self.content[start..]);
+        values
+    }
+
+    /// Truncates the document's content to `len` bytes, dropping the values of a `multi_valued`
+    /// document that start past the new end. Panics if `len` isn't at a char boundary.
+    pub fn truncate(&mut self, len: usize) {
+        self.content.truncate(len);
+        self.value_starts.retain(|&start| start <= len);
+    }
+
+    /// Returns a reference to the document's metadata, if it has any
+    pub fn metadata(&self) -> Option<&str> {
+        self.metadata.as_ref().map(|metadata| &metadata[..])
+    }
+}
+
+/// Returns the index of the value of the document that the byte offset in its content falls in;
+/// always 0 unless the document was constructed by `Document::multi_valued`.
+pub fn value_index(doc: &Document, offset: usize) -> usize {
+    doc.value_starts.iter().take_while(|&&start| start <= offset).count()
 }
@@ -1,22 +1,251 @@
-use std::collections::Bound::{Included, Excluded, Unbounded};
-use std::collections::BTreeMap;
-use std::hash::Hasher;
+use std::cmp::{self, Ordering};
+use std::usize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ops::Bound::{Included, Excluded, Unbounded};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
+use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
 
 use Query::*;
+use document::value_index;
+use search_result::{TotalF32, compare_results};
 use super::*;
 use util::*;
 
 /// A basic implementation of an `Index`, the inverted index is a data structure that maps
 /// from words to postings.
-#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, 
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd,
          RustcEncodable, RustcDecodable)]
 pub struct InvertedIndex {
     // Maps terms to their postings
     index: BTreeMap<String, PostingsMap>,
     // Maps doc ids to their docs
     docs: BTreeMap<usize, Document>,
+    // The maximum number of positions recorded per term per document, if any
+    max_positions_per_posting: Option<usize>,
+    // The maximum number of postings, i.e. distinct terms, a document may add to the index, if any
+    max_total_postings_per_doc: Option<usize>,
+    // Whether postings omit positions, recording only which documents contain each term
+    omit_positions: bool,
+    // Whether And queries boost documents whose matching terms are close together
+    and_proximity_boost: bool,
+    // The maximum score boost given to the newest document
+    recency_boost: TotalF32,
+    // The path of the log that mutations are appended to, if any
+    log: Transient<Option<PathBuf>>,
+    // The locale whose casing rules are used to lowercase documents and queries
+    locale: Locale,
+    // The measure of edit distance used by fuzzy queries
+    fuzzy_kind: FuzzyKind,
+    // How results are scored from the length of their matching content
+    scoring_mode: ScoringMode,
+    // Whether the length of each matching term is weighted by the term's IDF when scoring
+    idf_weighting: bool,
+    // The mutations staged since `begin_batch`, if a batch is open
+    batch: Transient<Option<Vec<LogOp>>>,
+    // The postings of the prefixes passed to `precompute_prefixes`, until the next mutation
+    prefix_cache: Transient<BTreeMap<String, PostingsMap>>,
+    // The summed content length of the indexed documents, maintained as documents are indexed
+    // and removed
+    total_content_len: usize,
+    // The sequence number assigned to the next inserted document
+    next_sequence: u64,
+    // Maps doc ids to their docs' insertion sequence numbers, which order results with equal
+    // scores
+    sequences: BTreeMap<usize, u64>,
+    // The lowercase words left out of documents and queries
+    stop_words: BTreeSet<String>,
+    // Whether the numbers in documents are indexed for `NumericRange` queries
+    index_numbers: bool,
+    // Maps the numbers occurring in documents to the documents containing them
+    numbers: BTreeMap<i64, BTreeSet<usize>>,
+    // Maps doc ids to their docs' length norms, precomputed for scoring
+    length_norms: BTreeMap<usize, TotalF32>,
+}
+
+/// The outcome of inserting a document into an index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Inserted {
+    /// The index didn't contain a document with the same id.
+    New,
+    /// The document replaced this document, which had the same id.
+    Replaced(Document),
+    /// The index already contained a document with the same id, which was kept, so the document
+    /// wasn't inserted. Only returned by `InvertedIndex::index_with_mode` with `IndexMode::Skip`.
+    Skipped,
+}
+
+/// What `InvertedIndex::index_with_mode` does when the index already contains a document with the
+/// same id.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IndexMode {
+    /// Replace the existing document, as `index` does.
+    Replace,
+    /// Return an `IndexError::DuplicateId` error, leaving the index unchanged.
+    Error,
+    /// Keep the existing document, leaving the index unchanged.
+    Skip,
+}
+
+/// An error preventing a document from being indexed, or an inconsistency found by
+/// `InvertedIndex::validate`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IndexError {
+    /// The document would add more postings to the index than allowed by
+    /// `set_max_total_postings_per_doc`.
+    TooManyPostings {
+        /// The id of the document.
+        doc_id: usize,
+        /// The number of postings the document would add.
+        postings: usize,
+        /// The maximum number of postings allowed per document.
+        max: usize,
+    },
+    /// The index already contains a document with the same id, and the document was indexed with
+    /// `IndexMode::Error`.
+    DuplicateId {
+        /// The id of the document.
+        doc_id: usize,
+    },
+    /// A term's postings reference a document that isn't in the index.
+    MissingDocument {
+        /// The term.
+        term: String,
+        /// The id of the missing document.
+        doc_id: usize,
+    },
+    /// Appending the change to the index's log failed, so the index was left unchanged.
+    Log {
+        /// The kind of the IO error.
+        kind: io::ErrorKind,
+        /// The path of the log and the IO error.
+        message: String,
+    },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexError::TooManyPostings { doc_id, postings, max } => {
+                write!(f,
+                       "document {} has {} postings, more than the maximum of {}",
+                       doc_id,
+                       postings,
+                       max)
+            }
+            IndexError::DuplicateId { doc_id } => {
+                write!(f, "a document with id {} is already indexed", doc_id)
+            }
+            IndexError::MissingDocument { ref term, doc_id } => {
+                write!(f, "postings of term {:?} reference missing document {}", term, doc_id)
+            }
+            IndexError::Log { ref message, .. } => {
+                write!(f, "failed to append to the index log at {}", message)
+            }
+        }
+    }
+}
+
+impl error::Error for IndexError {
+    fn description(&self) -> &str {
+        match *self {
+            IndexError::TooManyPostings { .. } => "document has too many postings",
+            IndexError::DuplicateId { .. } => "a document with the same id is already indexed",
+            IndexError::MissingDocument { .. } => "postings reference a missing document",
+            IndexError::Log { .. } => "failed to append to the index log",
+        }
+    }
+}
+
+/// Postings produced lazily, in ascending doc id order.
+type LazyPostings<'a> = Box<Iterator<Item = (usize, Vec<Position>)> + 'a>;
+
+/// The tokenizer used to analyze documents.
+type DocAnalyzer = LowercaseFilter<NgramsFilter<StopWordsFilter<EnglishUtf8<io::Cursor<Vec<u8>>>>>>;
+
+/// The number of token positions separating consecutive values of a multi-valued document.
+const VALUE_POSITION_GAP: usize = 100;
+
+/// A mutation of an index, as recorded in its log or staged in a batch.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, RustcEncodable, RustcDecodable)]
+enum LogOp {
+    Index(Document),
+    Remove(usize),
+}
+
+/// State that's kept alongside an index's contents but isn't part of them: it's ignored when
+/// indexes are compared or hashed, isn't encoded, and is cloned and decoded as its default value,
+/// so that e.g. a copy of an index doesn't append to the original's log.
+#[derive(Debug, Default)]
+struct Transient<T>(T);
+
+impl<T: Default> Clone for Transient<T> {
+    fn clone(&self) -> Transient<T> {
+        Transient::default()
+    }
+}
+
+impl<T> Deref for Transient<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Transient<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> PartialEq for Transient<T> {
+    fn eq(&self, _: &Transient<T>) -> bool {
+        true
+    }
+}
+
+impl<T> Eq for Transient<T> {}
+
+impl<T> PartialOrd for Transient<T> {
+    fn partial_cmp(&self, other: &Transient<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Transient<T> {
+    fn cmp(&self, _: &Transient<T>) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+impl<T> Hash for Transient<T> {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+impl<T> Encodable for Transient<T> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_nil()
+    }
+}
+
+impl<T: Default> Decodable for Transient<T> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Transient<T>, D::Error> {
+        try!(d.read_nil());
+        Ok(Transient::default())
+    }
 }
 
 impl InvertedIndex {
@@ -25,383 +254,4078 @@ impl InvertedIndex {
         InvertedIndex {
             index: BTreeMap::new(),
             docs: BTreeMap::new(),
+            max_positions_per_posting: None,
+            max_total_postings_per_doc: None,
+            omit_positions: false,
+            and_proximity_boost: false,
+            recency_boost: TotalF32(0.),
+            log: Transient::default(),
+            locale: Locale::Default,
+            fuzzy_kind: FuzzyKind::Levenshtein,
+            scoring_mode: ScoringMode::default(),
+            idf_weighting: false,
+            batch: Transient::default(),
+            prefix_cache: Transient::default(),
+            total_content_len: 0,
+            next_sequence: 0,
+            sequences: BTreeMap::new(),
+            stop_words: BTreeSet::new(),
+            index_numbers: false,
+            numbers: BTreeMap::new(),
+            length_norms: BTreeMap::new(),
+        }
+    }
+
+    /// Opens an index backed by an append-only log at `path`. If the log exists, the operations
+    /// recorded in it are replayed to recover the index; otherwise, the log is created on the
+    /// first mutation. From then on, every `index` and `remove` is appended to the log.
+    ///
+    /// The log is replayed with the default index settings; use `with_log` to replay it with
+    /// others. A partially written final entry, e.g. from a crash during an append, is discarded.
+    /// Appends aren't synced to disk, so the log protects against process crashes, but not
+    /// necessarily against operating system crashes. Clones and decoded copies of the index
+    /// aren't backed by the log.
+    pub fn open_with_log<P: AsRef<Path>>(path: P) -> io::Result<InvertedIndex> {
+        InvertedIndex::new().with_log(path)
+    }
+
+    /// Backs the index by an append-only log at `path` like `open_with_log`, replaying the log
+    /// with the index's current settings, e.g. its stop words, locale and whether numbers are
+    /// indexed. These should be the settings the log was written with, since the index is
+    /// recovered by reindexing the logged documents.
+    ///
+    /// Returns an error of kind `InvalidInput` if the index already contains documents.
+    pub fn with_log<P: AsRef<Path>>(mut self, path: P) -> io::Result<InvertedIndex> {
+        if !self.docs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "only an empty index can be backed by a log"));
+        }
+        let path = path.as_ref();
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut entry = vec![];
+                let mut valid_len = 0;
+                loop {
+                    entry.clear();
+                    let len = try!(reader.read_until(b'\n', &mut entry));
+                    if len == 0 {
+                        break;
+                    }
+                    let complete = entry.ends_with(b"\n");
+                    let decoded = str::from_utf8(&entry)
+                                      .map_err(|err| err.to_string())
+                                      .and_then(|line| {
+                                          json::decode(line.trim_right())
+                                              .map_err(|err| err.to_string())
+                                      });
+                    match decoded {
+                        Ok(LogOp::Index(doc)) => {
+                            self.index(doc);
+                        }
+                        Ok(LogOp::Remove(doc_id)) => {
+                            self.remove(doc_id);
+                        }
+                        // Only the final entry can lack its newline, having been partially written
+                        Err(_) if !complete => break,
+                        Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                    }
+                    valid_len += len as u64;
+                    if !complete {
+                        // The entry was written but its newline wasn't, so that the next append
+                        // would continue its line
+                        try!(reader.get_mut().write_all(b"\n"));
+                        valid_len += 1;
+                    }
+                }
+                // Discard a partially written final entry, so that appends follow the last
+                // valid one
+                try!(reader.get_ref().set_len(valid_len));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        *self.log = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Rewrites the index's log as a snapshot of its current documents, discarding the history
+    /// of replaced and removed documents. The documents are written in the order they were
+    /// inserted, and renumbered as they will be when the log is replayed. Does nothing if the
+    /// index isn't backed by a log.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = match *self.log {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut compacted = path.clone().into_os_string();
+        compacted.push(".compact");
+        {
+            let mut file = BufWriter::new(try!(File::create(&compacted)));
+            let mut docs: Vec<_> = self.docs.values().collect();
+            docs.sort_by_key(|doc| self.sequence(doc.id));
+            for doc in docs {
+                try!(writeln!(file, "{}", json::encode(&LogOp::Index(doc.clone())).unwrap()));
+            }
+            try!(file.flush());
+        }
+        try!(fs::rename(&compacted, path));
+        let mut doc_ids: Vec<_> = self.sequences.keys().cloned().collect();
+        doc_ids.sort_by_key(|&doc_id| self.sequence(doc_id));
+        self.next_sequence = 0;
+        for doc_id in doc_ids {
+            let sequence = self.next_sequence();
+            self.sequences.insert(doc_id, sequence);
+        }
+        Ok(())
+    }
+
+    /// Caps the number of positions recorded for each term in each document indexed from now on.
+    /// Once a document's postings for a term reach the cap, further occurrences of the term in
+    /// that document are not recorded, bounding the memory used by pathologically repetitive
+    /// documents.
+    ///
+    /// This trades accuracy for memory: scores only account for the recorded positions, and
+    /// `Phrase` queries can miss matches that involve unrecorded occurrences.
+    pub fn set_max_positions_per_posting(&mut self, max_positions: Option<usize>) {
+        self.max_positions_per_posting = max_positions;
+    }
+
+    /// Caps the number of postings, i.e. distinct terms including the ngrams of each word, that a
+    /// single document may add to the index. Documents with many long words expand to many
+    /// ngrams, so this catches pathological inputs: `try_index` returns an error for documents
+    /// exceeding the cap, and `index` panics.
+    pub fn set_max_total_postings_per_doc(&mut self, max_postings: Option<usize>) {
+        self.max_total_postings_per_doc = max_postings;
+    }
+
+    /// Enables or disables recording the positions of terms in the documents indexed from now on.
+    /// Positions are recorded by default. When they aren't, each posting only records that the
+    /// document contains the term, which saves memory when only boolean matching is needed.
+    ///
+    /// Without positions, documents still match `Match`, `Term`, `Prefix`, `Literal`, `And` and
+    /// `Or` queries, but their results aren't highlighted and, except for `Literal` queries, all
+    /// score 0 besides any recency boost. `Phrase` and `PhrasePrefix` queries match no
+    /// documents, since the order of their terms can't be checked.
+    pub fn set_track_positions(&mut self, enabled: bool) {
+        self.omit_positions = !enabled;
+    }
+
+    /// Enables or disables boosting the results of top-level `And` queries by how closely their
+    /// sub-queries' matches are clustered. When enabled, each result's score is multiplied by
+    /// `1 + 1 / (1 + span)`, where `span` is the smallest number of token positions covering one
+    /// match of each sub-query; sub-queries matching adjacent words have a span of 1.
+    pub fn set_and_proximity_boost(&mut self, enabled: bool) {
+        self.and_proximity_boost = enabled;
+    }
+
+    /// Sets the boost given to recent documents, treating documents with higher ids as newer,
+    /// e.g. when ids are timestamps. Each result's score is increased by `boost` multiplied by
+    /// its doc id divided by the highest doc id in the index, so that the newest document
+    /// receives the full boost. The boost is 0, i.e. disabled, by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the boost is infinite or NaN.
+    pub fn set_recency_boost(&mut self, boost: f32) {
+        assert!(boost.is_finite(), "the recency boost must be finite");
+        self.recency_boost = TotalF32(boost);
+    }
+
+    /// Sets the locale whose casing rules are used to lowercase documents and queries, e.g.
+    /// `Locale::Turkish` so that "İstanbul" matches "istanbul". Documents and queries must be
+    /// lowercased the same way, so this must be set before any documents are indexed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index contains documents, whose postings were analyzed with the previous
+    /// locale and couldn't be found to be removed once they're replaced or removed.
+    pub fn set_locale(&mut self, locale: Locale) {
+        assert!(self.docs.is_empty(), "the locale can't be changed once documents are indexed");
+        self.locale = locale;
+    }
+
+    /// Sets the words, given in lowercase, that are left out of documents and queries, e.g. "the"
+    /// and "a", which match too many documents to be useful. Stop words still count towards the
+    /// positions of the remaining words, so phrase and proximity queries skip over them: `Phrase`
+    /// queries for "quick fox" and "the quick fox" both match "the quick fox", but not "quick the
+    /// fox", while a stop word within a phrase matches any single word. Like the locale, this
+    /// must be set before any documents are indexed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index contains documents, whose postings were analyzed with the previous
+    /// stop words and couldn't be found to be removed once they're replaced or removed.
+    pub fn set_stop_words(&mut self, stop_words: BTreeSet<String>) {
+        assert!(self.docs.is_empty(), "the stop words can't be changed once documents are indexed");
+        self.stop_words = stop_words;
+    }
+
+    /// Enables or disables indexing the numbers in the documents indexed from now on, i.e. the
+    /// integers among their words, such as years, the 10 in "$10", "-5" or "1,000", so that they
+    /// can be searched with `NumericRange` queries. Decimals such as "1.5" aren't indexed as
+    /// numbers. Numbers are still indexed as text, too.
+    pub fn set_index_numbers(&mut self, enabled: bool) {
+        self.index_numbers = enabled;
+    }
+
+    /// Sets how `Fuzzy` queries measure the distance between words. The Levenshtein distance is
+    /// used by default.
+    pub fn set_fuzzy_kind(&mut self, kind: FuzzyKind) {
+        self.fuzzy_kind = kind;
+    }
+
+    /// Sets how results are scored from the length of their matching content, e.g. which
+    /// function of a document's length scores are divided by. By default, scores are normalized
+    /// by the square root of the document's length.
+    pub fn set_scoring_mode(&mut self, mode: ScoringMode) {
+        self.scoring_mode = mode;
+        for (doc_id, doc) in &self.docs {
+            self.length_norms.insert(*doc_id, TotalF32(mode.length_norm(doc.content.len())));
         }
     }
 
+    /// Sets whether the length of each matching term is multiplied by the term's `idf` when
+    /// scoring results, so that matching a rare term counts for more than matching a common term
+    /// of the same length. Disabled by default.
+    ///
+    /// The term of each position is the term its content is indexed under, e.g. "dont" for
+    /// "don't", i.e. the longest indexed term the position matched.
+    pub fn set_idf_weighting(&mut self, enabled: bool) {
+        self.idf_weighting = enabled;
+    }
+
+    /// Hints that about `expected_docs` more documents are about to be indexed.
+    ///
+    /// The index is currently backed by `BTreeMap`s, which allocate per node and can't reserve
+    /// capacity up front, so this is a no-op. It exists so that bulk-indexing code can provide
+    /// the hint now and benefit if the backing maps change.
+    pub fn reserve(&mut self, expected_docs: usize) {
+        let _ = expected_docs;
+    }
+
     /// Inserts the document.
     /// Insertings a document involves tokenizing the document's content
     /// and inserting each token into the index, pointing to the document and its position in the
     /// document.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is backed by a log and appending to it fails, or if the document
+    /// exceeds the cap set by `set_max_total_postings_per_doc`. Use `try_index` to handle either.
     pub fn index(&mut self, doc: Document) {
+        self.insert(doc);
+    }
+
+    /// Indexes a document for each of the given pairs of document id and content, like `index`,
+    /// without requiring the caller to construct the documents.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `index`.
+    pub fn index_pairs<'a, I>(&mut self, pairs: I)
+        where I: IntoIterator<Item = (usize, &'a str)>
+    {
+        for (id, content) in pairs {
+            self.index(Document::new(id, content));
+        }
+    }
+
+    /// Inserts the document like `index`, returning whether it's new or replaced an existing
+    /// document with the same id, in which case the replaced document is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `index`.
+    pub fn insert(&mut self, doc: Document) -> Inserted {
+        match self.insert_checked(doc) {
+            Ok(Some(previous_version)) => Inserted::Replaced(previous_version),
+            Ok(None) => Inserted::New,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Inserts the document like `insert`, except that if the index already contains a document
+    /// with the same id, `mode` determines whether it's replaced, an error is returned, or the
+    /// document is skipped, e.g. for pipelines that assume unique ids. Documents exceeding the
+    /// postings cap are rejected as by `try_index`, as are documents whose insertion couldn't be
+    /// appended to the index's log. While a batch is open, only committed documents count as
+    /// already indexed.
+    pub fn index_with_mode(&mut self, doc: Document, mode: IndexMode)
+                           -> Result<Inserted, IndexError> {
+        if self.docs.contains_key(&doc.id) {
+            match mode {
+                IndexMode::Replace => {}
+                IndexMode::Error => return Err(IndexError::DuplicateId { doc_id: doc.id }),
+                IndexMode::Skip => return Ok(Inserted::Skipped),
+            }
+        }
+        self.insert_checked(doc).map(|previous_version| {
+            match previous_version {
+                Some(previous_version) => Inserted::Replaced(previous_version),
+                None => Inserted::New,
+            }
+        })
+    }
+
+    /// Inserts the document like `index`, unless that would exceed the cap on the number of
+    /// postings per document set by `set_max_total_postings_per_doc`, or the index is backed by a
+    /// log and appending to it fails. In either case, the index is left unchanged and an error
+    /// identifying the problem is returned.
+    pub fn try_index(&mut self, doc: Document) -> Result<(), IndexError> {
+        self.insert_checked(doc).map(|_| ())
+    }
+
+    /// Inserts the document unless it exceeds the postings cap, returning the document it
+    /// replaced, if any.
+    fn insert_checked(&mut self, doc: Document) -> Result<Option<Document>, IndexError> {
+        let postings = self.doc_postings(&doc);
+        try!(self.check_postings(&doc, &postings));
+        if let Some(ref mut batch) = *self.batch {
+            let previous_version = self.docs.get(&doc.id).cloned();
+            batch.push(LogOp::Index(doc));
+            return Ok(previous_version);
+        }
+        if self.log.is_some() {
+            try!(self.append_to_log(&[LogOp::Index(doc.clone())]));
+        }
+        let sequence = self.next_sequence();
+        self.sequences.insert(doc.id, sequence);
+        self.prefix_cache.clear();
+        self.total_content_len += doc.content.len();
         let previous_version = self.docs.insert(doc.id, doc.clone());
-        if let Some(previous_version) = previous_version {
-            let previous_analyzed = lowercase_ngrams(previous_version.content)
-                                        .into_iter()
-                                        .map(Result::unwrap);
-            for Token { token, .. } in previous_analyzed {
-                let is_empty = {
-                    let docs_for_ngram = self.index.get_mut(&token).unwrap();
+        if let Some(ref previous_version) = previous_version {
+            self.total_content_len -= previous_version.content.len();
+            self.remove_postings(previous_version);
+        }
+
+        for (term, positions) in postings {
+            self.index.entry(term).or_insert_with(BTreeMap::new).insert(doc.id, positions);
+        }
+        self.add_numbers(&doc);
+        let norm = self.scoring_mode.length_norm(doc.content.len());
+        self.length_norms.insert(doc.id, TotalF32(norm));
+        Ok(previous_version)
+    }
+
+    /// Returns the sequence number of a newly inserted document.
+    fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Returns the sequence number of the document with the given id.
+    fn sequence(&self, doc_id: usize) -> u64 {
+        self.sequences.get(&doc_id).cloned().unwrap_or(0)
+    }
+
+    fn check_postings(&self,
+                      doc: &Document,
+                      postings: &BTreeMap<String, Vec<Position>>)
+                      -> Result<(), IndexError> {
+        match self.max_total_postings_per_doc {
+            Some(max) if postings.len() > max => {
+                Err(IndexError::TooManyPostings {
+                    doc_id: doc.id,
+                    postings: postings.len(),
+                    max: max,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Inserts the documents, replacing any existing versions, with the same result as inserting
+    /// them one at a time with `index`. The changes to the postings of the whole batch are
+    /// computed first and then applied term by term, so each affected term is looked up once
+    /// rather than once per occurrence in each document.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `update_all_checked` returns an error, in which case
+    /// none of the documents are inserted.
+    pub fn update_all<I>(&mut self, docs: I)
+        where I: IntoIterator<Item = Document>
+    {
+        if let Err(err) = self.update_all_checked(docs) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Inserts the documents like `update_all`, unless any of them exceeds the cap on the number
+    /// of postings per document set by `set_max_total_postings_per_doc`, or the index is backed
+    /// by a log and appending to it fails. In either case, the index is left unchanged and an
+    /// error identifying the problem is returned.
+    pub fn update_all_checked<I>(&mut self, docs: I) -> Result<(), IndexError>
+        where I: IntoIterator<Item = Document>
+    {
+        // Only the last version of each document in the batch affects the final postings. Each
+        // document is ordered as if inserted on its own, so a later version of a document is
+        // ordered after the documents inserted between it and earlier versions
+        let mut batch = BTreeMap::new();
+        let mut inserted = 0;
+        for doc in docs {
+            batch.insert(doc.id, (inserted, doc));
+            inserted += 1;
+        }
+        let mut batch_postings = Vec::with_capacity(batch.len());
+        for &(_, ref doc) in batch.values() {
+            let postings = self.doc_postings(&doc);
+            try!(self.check_postings(doc, &postings));
+            batch_postings.push(postings);
+        }
+        let mut order: Vec<_> = batch.iter().map(|(&doc_id, &(i, _))| (i, doc_id)).collect();
+        order.sort();
+        let ops: Vec<_> = order.into_iter()
+                               .map(|(_, doc_id)| LogOp::Index(batch[&doc_id].1.clone()))
+                               .collect();
+        if let Some(ref mut staged) = *self.batch {
+            staged.extend(ops);
+            return Ok(());
+        }
+        // Log the documents in the order they're sequenced, so that replaying the log sequences
+        // them in the same order
+        try!(self.append_to_log(&ops));
+
+        self.prefix_cache.clear();
+        for (&doc_id, &(i, _)) in &batch {
+            self.sequences.insert(doc_id, self.next_sequence + i);
+        }
+        self.next_sequence += inserted;
+        let mut removed = BTreeMap::new();
+        let mut added = BTreeMap::new();
+        for ((doc_id, (_, doc)), postings) in batch.into_iter().zip(batch_postings) {
+            if let Some(previous_version) = self.docs.get(&doc_id).cloned() {
+                self.remove_numbers(&previous_version);
+                let analyzed = self.analyze(previous_version.content()).into_iter();
+                for Token { token, .. } in analyzed.map(Result::unwrap) {
+                    removed.entry(token).or_insert_with(Vec::new).push(doc_id);
+                }
+            }
+            for (term, positions) in postings {
+                added.entry(term).or_insert_with(Vec::new).push((doc_id, positions));
+            }
+            self.total_content_len += doc.content.len();
+            self.add_numbers(&doc);
+            let norm = self.scoring_mode.length_norm(doc.content.len());
+            self.length_norms.insert(doc_id, TotalF32(norm));
+            if let Some(previous_version) = self.docs.insert(doc_id, doc) {
+                self.total_content_len -= previous_version.content.len();
+            }
+        }
+
+        let mut emptied = vec![];
+        for (term, doc_ids) in removed {
+            if let Some(postings) = self.index.get_mut(&term) {
+                for doc_id in doc_ids {
+                    postings.remove(&doc_id);
+                }
+                if postings.is_empty() && !added.contains_key(&term) {
+                    emptied.push(term);
+                }
+            }
+        }
+        for term in emptied {
+            self.index.remove(&term);
+        }
+        for (term, postings) in added {
+            self.index.entry(term).or_insert_with(BTreeMap::new).extend(postings);
+        }
+        Ok(())
+    }
+
+    /// Analyzes the content of a document, returning the positions of each of its terms as they
+    /// should be recorded in the document's postings.
+    ///
+    /// The values of multi-valued documents are separated by a gap of `VALUE_POSITION_GAP` token
+    /// positions, so that phrase and proximity queries don't match across values.
+    fn doc_postings(&self, doc: &Document) -> BTreeMap<String, Vec<Position>> {
+        let mut postings = BTreeMap::new();
+        let analyzed = self.analyze(&doc.content).into_iter().map(Result::unwrap);
+        for Token { token, mut position } in analyzed {
+            position.position += value_index(doc, position.offsets.0) * VALUE_POSITION_GAP;
+            let positions = postings.entry(token).or_insert_with(Vec::new);
+            if !self.omit_positions &&
+               self.max_positions_per_posting.map_or(true, |max| positions.len() < max) {
+                positions.search_coalesce(0, position);
+            }
+        }
+        postings
+    }
+
+    /// Opens a batch: from now on, `index`, `insert`, `try_index`, `update_all` and `remove` stage
+    /// their changes instead of applying them, until `commit` applies them all at once or
+    /// `rollback` discards them. Queries don't see the staged changes, and the values returned
+    /// by the staging methods describe the index as of the last commit. Documents exceeding the
+    /// postings cap are rejected when staged. Does nothing if a batch is already open.
+    pub fn begin_batch(&mut self) {
+        if self.batch.is_none() {
+            *self.batch = Some(vec![]);
+        }
+    }
+
+    /// Applies the changes staged since `begin_batch` and closes the batch. Only the last staged
+    /// change to each document matters, and the remaining insertions are applied together, as
+    /// by `update_all`. Does nothing if no batch is open.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is backed by a log and appending to it fails.
+    pub fn commit(&mut self) {
+        let batch = match self.batch.take() {
+            Some(batch) => batch,
+            None => return,
+        };
+        let mut changes = BTreeMap::new();
+        for (i, op) in batch.into_iter().enumerate() {
+            match op {
+                LogOp::Index(doc) => changes.insert(doc.id, (i, Some(doc))),
+                LogOp::Remove(doc_id) => changes.insert(doc_id, (i, None)),
+            };
+        }
+        let mut docs = vec![];
+        for (doc_id, (i, change)) in changes {
+            match change {
+                Some(doc) => docs.push((i, doc)),
+                None => {
+                    self.remove(doc_id);
+                }
+            }
+        }
+        // Insert the documents in the order they were staged, so they're sequenced accordingly
+        docs.sort_by_key(|&(i, _)| i);
+        self.update_all(docs.into_iter().map(|(_, doc)| doc));
+    }
+
+    /// Discards the changes staged since `begin_batch` and closes the batch.
+    pub fn rollback(&mut self) {
+        *self.batch = None;
+    }
+
+    /// Removes the document with the given id from the index, returning it if it was present.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is backed by a log and appending to it fails. Use `try_remove` to
+    /// handle that.
+    pub fn remove(&mut self, doc_id: usize) -> Option<Document> {
+        match self.try_remove(doc_id) {
+            Ok(removed) => removed,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Removes the document with the given id from the index like `remove`, unless the index is
+    /// backed by a log and appending to it fails, in which case the index is left unchanged and
+    /// an error is returned.
+    pub fn try_remove(&mut self, doc_id: usize) -> Result<Option<Document>, IndexError> {
+        if let Some(ref mut batch) = *self.batch {
+            batch.push(LogOp::Remove(doc_id));
+            return Ok(self.docs.get(&doc_id).cloned());
+        }
+        if !self.docs.contains_key(&doc_id) {
+            return Ok(None);
+        }
+        try!(self.append_to_log(&[LogOp::Remove(doc_id)]));
+        let removed = self.docs.remove(&doc_id);
+        if let Some(ref doc) = removed {
+            self.prefix_cache.clear();
+            self.total_content_len -= doc.content.len();
+            self.length_norms.remove(&doc_id);
+            self.sequences.remove(&doc_id);
+            self.remove_postings(doc);
+        }
+        Ok(removed)
+    }
+
+    /// Rebuilds the index's postings from its documents, e.g. after many documents were removed or
+    /// replaced, so that the postings take up no more memory than those of an index into which
+    /// just the current documents were inserted.
+    ///
+    /// The documents are reindexed with the current settings, e.g. the position caps, and terms
+    /// removed by `prune_terms` are restored.
+    pub fn vacuum(&mut self) {
+        let mut index = BTreeMap::new();
+        for doc in self.docs.values() {
+            for (term, mut positions) in self.doc_postings(doc) {
+                positions.shrink_to_fit();
+                index.entry(term).or_insert_with(BTreeMap::new).insert(doc.id, positions);
+            }
+        }
+        self.index = index;
+        self.prefix_cache.clear();
+        self.numbers.clear();
+        if self.index_numbers {
+            for doc in self.docs.values() {
+                for number in numbers(doc.content()) {
+                    self.numbers.entry(number).or_insert_with(BTreeSet::new).insert(doc.id);
+                }
+            }
+        }
+    }
+
+    /// Removes the terms contained in fewer than `min_df` documents, or in more than
+    /// `max_df_ratio` of all documents, from the index, e.g. to cap its size by dropping typos
+    /// and terms too common to be useful.
+    ///
+    /// Only the terms' postings are removed; the documents stay indexed under their other terms.
+    /// Pruned terms become unsearchable: queries for them match nothing, until they're indexed
+    /// again as part of a newly indexed document. Since words are indexed under each of their
+    /// prefixes, pruning common terms typically removes the short prefixes of many words.
+    pub fn prune_terms(&mut self, min_df: usize, max_df_ratio: f32) {
+        let max_df = max_df_ratio * self.docs.len() as f32;
+        let pruned: Vec<_> = self.index
+                                 .iter()
+                                 .filter(|&(_, postings)| {
+                                     postings.len() < min_df || postings.len() as f32 > max_df
+                                 })
+                                 .map(|(term, _)| term.clone())
+                                 .collect();
+        if !pruned.is_empty() {
+            self.prefix_cache.clear();
+        }
+        for term in &pruned {
+            self.index.remove(term);
+        }
+    }
+
+    /// Checks that the index is consistent, i.e. that the postings of every term only reference
+    /// indexed documents, returning the first inconsistency found otherwise. Queries skip
+    /// postings of missing documents rather than panicking, so an inconsistent index goes
+    /// unnoticed unless validated, e.g. with `debug_assert!(index.validate().is_ok())` after
+    /// mutating it.
+    pub fn validate(&self) -> Result<(), IndexError> {
+        for (term, postings) in &self.index {
+            for &doc_id in postings.keys() {
+                if !self.docs.contains_key(&doc_id) {
+                    return Err(IndexError::MissingDocument {
+                        term: term.clone(),
+                        doc_id: doc_id,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the tokenizer used to analyze documents: lowercased ngrams of their words, except
+    /// for stop words.
+    fn analyze(&self, content: &str) -> DocAnalyzer {
+        let words = StopWordsFilter::ignoring_case(EnglishUtf8::from_bytes(content),
+                                                   self.stop_words.clone(),
+                                                   self.locale);
+        LowercaseFilter::with_locale(NgramsFilter::after_tokenizer(words), self.locale)
+    }
+
+    /// Returns the analyzer used for queries by default: it tokenizes queries into their
+    /// lowercased words, except for stop words.
+    fn query_analyzer(&self) -> QueryAnalyzer {
+        QueryAnalyzer {
+            locale: self.locale,
+            stop_words: self.stop_words.clone(),
+        }
+    }
+
+    /// Indexes the numbers in the document, if numbers are indexed.
+    fn add_numbers(&mut self, doc: &Document) {
+        if !self.index_numbers {
+            return;
+        }
+        for number in numbers(doc.content()) {
+            self.numbers.entry(number).or_insert_with(BTreeSet::new).insert(doc.id);
+        }
+    }
+
+    fn remove_numbers(&mut self, doc: &Document) {
+        if self.numbers.is_empty() {
+            return;
+        }
+        for number in numbers(doc.content()) {
+            let is_empty = match self.numbers.get_mut(&number) {
+                Some(doc_ids) => {
+                    doc_ids.remove(&doc.id);
+                    doc_ids.is_empty()
+                }
+                None => continue,
+            };
+            if is_empty {
+                self.numbers.remove(&number);
+            }
+        }
+    }
+
+    fn remove_postings(&mut self, doc: &Document) {
+        self.remove_numbers(doc);
+        let analyzed = self.analyze(doc.content()).into_iter().map(Result::unwrap);
+        for Token { token, .. } in analyzed {
+            let is_empty = match self.index.get_mut(&token) {
+                Some(docs_for_ngram) => {
                     docs_for_ngram.remove(&doc.id);
                     docs_for_ngram.is_empty()
-                };
-                if is_empty {
-                    self.index.remove(&token);
+                }
+                // The term occurs more than once in the document and was already removed
+                None => continue,
+            };
+            if is_empty {
+                self.index.remove(&token);
+            }
+        }
+    }
+
+    fn append_to_log(&self, ops: &[LogOp]) -> Result<(), IndexError> {
+        if let Some(ref path) = *self.log {
+            // Written in one call, so that a crash can't separate an entry from its newline, and
+            // a failed append of several entries doesn't leave some of them behind
+            let mut entry = String::new();
+            for op in ops {
+                entry.push_str(&json::encode(op).unwrap());
+                entry.push('\n');
+            }
+            let appended = OpenOptions::new()
+                               .create(true)
+                               .append(true)
+                               .open(path)
+                               .and_then(|mut file| file.write_all(entry.as_bytes()));
+            if let Err(err) = appended {
+                return Err(IndexError::Log {
+                    kind: err.kind(),
+                    message: format!("{:?}: {}", path, err),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the term vector of the document with the given id: each term indexed for the
+    /// document, mapped to the number of times it occurs in the document. The terms are the
+    /// document's analyzed ngrams, as stored in the index. Returns an empty map if there is no
+    /// such document.
+    ///
+    /// The vector is computed by re-analyzing the document's content, so this costs about as
+    /// much as indexing the document.
+    pub fn term_vector(&self, doc_id: usize) -> BTreeMap<String, usize> {
+        let mut term_vector = BTreeMap::new();
+        if let Some(doc) = self.docs.get(&doc_id) {
+            let analyzed = self.analyze(doc.content()).into_iter().map(Result::unwrap);
+            for Token { token, .. } in analyzed {
+                *term_vector.entry(token).or_insert(0) += 1;
+            }
+        }
+        term_vector
+    }
+
+    /// Returns the content of the document with the given id with the occurrences of the given
+    /// terms wrapped in `before` and `after`, or `None` if there is no such document. Like the
+    /// words of a `Match` query, each term is analyzed before being looked up, and highlights the
+    /// words it's a prefix of.
+    pub fn highlight_doc(&self,
+                         doc_id: usize,
+                         terms: &[&str],
+                         before: &str,
+                         after: &str)
+                         -> Option<String> {
+        self.docs.get(&doc_id).map(|doc| {
+            let mut positions = vec![];
+            for term in terms {
+                for token in self.query_analyzer().analyze(term).into_iter().map(Result::unwrap) {
+                    let postings = self.index.get(&token.token).and_then(|map| map.get(&doc_id));
+                    if let Some(postings) = postings {
+                        positions.merge_coalesce(postings.iter().cloned());
+                    }
+                }
+            }
+            SearchResult::new(doc, positions).highlight(before, after)
+        })
+    }
+
+    /// Returns an estimate of the heap memory used by the index, in bytes: the stored terms and
+    /// documents, the positions of their postings, and the per-entry overhead of the maps holding
+    /// them. The estimate ignores unused capacity and allocator overhead, so it isn't exact, but it
+    /// grows with the amount of indexed content.
+    pub fn estimated_memory(&self) -> usize {
+        // Approximates the share of a map node's pointers and bookkeeping attributed to an entry.
+        let entry_overhead = mem::size_of::<usize>();
+        let term_entry = mem::size_of::<(String, PostingsMap)>() + entry_overhead;
+        let posting_entry = mem::size_of::<(usize, Vec<Position>)>() + entry_overhead;
+        let doc_entry = mem::size_of::<(usize, Document)>() + entry_overhead;
+        let postings = self.index
+                           .iter()
+                           .map(|(term, postings)| {
+                               let positions = postings.values()
+                                                       .map(|positions| positions.len())
+                                                       .sum::<usize>();
+                               term_entry + term.len() + postings.len() * posting_entry +
+                               positions * mem::size_of::<Position>()
+                           })
+                           .sum::<usize>();
+        let docs = self.docs.values().map(|doc| doc_entry + doc.content.len()).sum::<usize>();
+        postings + docs
+    }
+
+    /// Returns statistics about the indexed documents, e.g. for scoring. They're maintained as
+    /// documents are indexed and removed, so this is cheap. The document frequency of each term
+    /// is likewise maintained, as the number of documents in its postings; see `doc_frequency`.
+    pub fn corpus_stats(&self) -> CorpusStats {
+        CorpusStats {
+            num_docs: self.docs.len(),
+            total_content_len: self.total_content_len,
+        }
+    }
+
+    /// Returns whether any document contains the given term, e.g. to cheaply rule out a query
+    /// before running it. The term is looked up verbatim, so it should be normalized the way
+    /// indexed terms are, e.g. lowercased. Since words are indexed under each of their prefixes,
+    /// prefixes of indexed words are terms too.
+    pub fn contains_term(&self, term: &str) -> bool {
+        self.index.contains_key(term)
+    }
+
+    /// Returns the number of documents containing the given term. The term is looked up
+    /// verbatim, so it should be normalized the way indexed terms are.
+    pub fn doc_frequency(&self, term: &str) -> usize {
+        self.index.get(term).map_or(0, BTreeMap::len)
+    }
+
+    /// Returns the inverse document frequency of the given term, computed BM25-style as
+    /// `ln(1 + (N - df + 0.5) / (df + 0.5))`, where `N` is the number of documents and `df` is
+    /// the term's `doc_frequency`. A term that isn't in the index has a `df` of 0, and so gets
+    /// the highest possible IDF.
+    pub fn idf(&self, term: &str) -> f32 {
+        let num_docs = self.docs.len() as f32;
+        let doc_frequency = self.doc_frequency(term) as f32;
+        (1. + (num_docs - doc_frequency + 0.5) / (doc_frequency + 0.5)).ln()
+    }
+
+    /// Performs a search to the specification of the given query.
+    /// Results are sorted by descending score; results with equal scores are sorted in the order
+    /// their documents were inserted.
+    pub fn query(&self, query: &Query) -> Vec<SearchResult> {
+        self.query_with(query, &self.query_analyzer(), &mut ())
+    }
+
+    /// Performs a search to the specification of the given query, like `query`, but analyzing the
+    /// query's text with the given analyzer instead of the index's default query analyzer, which
+    /// tokenizes text into its lowercased words. Documents are still matched against the terms
+    /// they were indexed under, i.e. the lowercased ngrams of their words.
+    ///
+    /// The analyzer's terms are looked up in the index verbatim, so an analyzer whose terms don't
+    /// resemble the indexed terms will silently match fewer documents, or none at all: e.g. terms
+    /// that aren't lowercased never match, and terms spanning several words never match phrase
+    /// queries. Conversely, an analyzer producing ngrams makes queries match documents containing
+    /// any prefix of the query's words.
+    pub fn query_with_analyzer<A: Analyzer>(&self, query: &Query, analyzer: &A)
+                                            -> Vec<SearchResult> {
+        self.query_with(query, analyzer, &mut ())
+    }
+
+    /// Performs a search to the specification of the given query, like `query`, but with each
+    /// score divided by the top result's score, so that scores lie between 0 and 1 and the top
+    /// result scores 1. This makes scores comparable across queries, e.g. for blending them with
+    /// other signals. If the top score is 0, e.g. when positions aren't tracked, the scores are
+    /// left at 0.
+    pub fn query_normalized(&self, query: &Query) -> Vec<SearchResult> {
+        let mut results = self.query(query);
+        let max_score = results.first().map_or(0., |result| result.score);
+        if max_score > 0. {
+            for result in &mut results {
+                result.score /= max_score;
+            }
+        }
+        results
+    }
+
+    /// Performs each of the given queries and blends their results into a single ranking, e.g. to
+    /// weight matches in titles over matches in bodies. Each document's score is the sum of its
+    /// scores for each query it matches, multiplied by the query's weight, and its positions are
+    /// the union of its positions for each query.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the weights is infinite or NaN.
+    pub fn query_blended(&self, queries: &[(Query, f32)]) -> Vec<SearchResult> {
+        let mut blended: BTreeMap<usize, SearchResult> = BTreeMap::new();
+        for &(ref query, weight) in queries {
+            assert!(weight.is_finite(), "query weights must be finite");
+            for result in self.query(query) {
+                let score = result.score * weight;
+                match blended.entry(result.doc.id) {
+                    Entry::Vacant(entry) => {
+                        let blended_result = entry.insert(result);
+                        blended_result.score = score;
+                    }
+                    Entry::Occupied(mut entry) => {
+                        let blended_result = entry.get_mut();
+                        blended_result.positions.merge_coalesce(result.positions);
+                        blended_result.score += score;
+                    }
                 }
             }
         }
+        let mut results: Vec<_> = blended.into_iter().map(|(_, result)| result).collect();
+        self.sort_results(&mut results);
+        results
+    }
+
+    /// Performs a search to the specification of the given query, like `query`, also attributing
+    /// each position of each result to the query term that produced it, in `SearchResult::terms`,
+    /// e.g. to highlight each term in a different color. When several terms match the same
+    /// word, the word is attributed to the first of them.
+    ///
+    /// Each leaf query is evaluated again to attribute the positions, so this costs about twice
+    /// as much as `query`.
+    pub fn query_with_terms(&self, query: &Query) -> Vec<SearchResult> {
+        let mut leaves = vec![];
+        collect_leaves(*query, &mut leaves);
+        let leaf_postings: Vec<_> = leaves.iter().map(|leaf| self.query_rec(leaf)).collect();
+        let mut results = self.query(query);
+        for result in &mut results {
+            let doc_id = result.doc.id;
+            let terms = result.positions
+                              .iter()
+                              .map(|position| {
+                                  let produced = |postings: &PostingsMap| {
+                                      postings.get(&doc_id).map_or(false, |positions| {
+                                          positions.iter().any(|leaf| overlaps(leaf, position))
+                                      })
+                                  };
+                                  leaf_postings.iter().position(produced).unwrap_or(0)
+                              })
+                              .collect();
+            result.terms = Some(terms);
+        }
+        results
+    }
+
+    /// Performs a search to the specification of the given query, like `query`, also returning
+    /// metrics describing the work done to execute it.
+    pub fn query_instrumented(&self, query: &Query) -> (Vec<SearchResult>, QueryMetrics) {
+        let mut recorder = Recorder {
+            metrics: QueryMetrics::default(),
+            matched_at: None,
+        };
+        let started_at = Instant::now();
+        let results = self.query_with(query, &self.query_analyzer(), &mut recorder);
+        let finished_at = Instant::now();
+        let mut metrics = recorder.metrics;
+        let matched_at = recorder.matched_at.unwrap_or(finished_at);
+        metrics.matching_time = matched_at.duration_since(started_at);
+        metrics.ranking_time = finished_at.duration_since(matched_at);
+        (results, metrics)
+    }
+
+    fn query_with<A, M>(&self, query: &Query, analyzer: &A, metrics: &mut M) -> Vec<SearchResult>
+        where A: Analyzer,
+              M: Metrics
+    {
+        let mut results = self.scored_results(query, analyzer, metrics);
+        self.rank(&mut results);
+        results
+    }
 
-        let analyzed = lowercase_ngrams(doc.content).into_iter().map(Result::unwrap);
+    /// Returns the results of the given query, scored but neither boosted nor sorted.
+    fn scored_results<A, M>(&self, query: &Query, analyzer: &A, metrics: &mut M)
+                            -> Vec<SearchResult>
+        where A: Analyzer,
+              M: Metrics
+    {
+        self.scored(query, analyzer, metrics).into_iter().map(into_result).collect()
+    }
 
-        for Token { token, position } in analyzed {
-            self.index
-                .entry(token)
-                .or_insert_with(BTreeMap::new)
-                .entry(doc.id)
-                .or_insert_with(Vec::new)
-                .search_coalesce(0, position);
+    /// Returns the matches of the given query, scored but neither boosted nor sorted. Every kind
+    /// of search scores its matches here, so that they agree on the scores of queries ranked by
+    /// how closely documents match, i.e. `Prefix`, `Near` and proximity-boosted `And` queries.
+    fn scored<A, M>(&self, query: &Query, analyzer: &A, metrics: &mut M) -> Vec<Scored>
+        where A: Analyzer,
+              M: Metrics
+    {
+        match *query {
+            And(queries) if self.and_proximity_boost && !self.omit_positions => {
+                self.and_with_proximity(queries, analyzer, metrics)
+            }
+            Prefix(prefix) => self.prefix_ranked(prefix, metrics),
+            Near(phrase, slop) => self.near_ranked(phrase, slop, analyzer, metrics),
+            _ => {
+                let postings = self.query_rec_with(query, analyzer, metrics);
+                metrics.matched();
+                self.score_postings(postings)
+            }
         }
     }
 
-    /// Performs a search to the specification of the given query
-    pub fn query(&self, query: &Query) -> Vec<SearchResult> {
-        let postings = self.query_rec(query);
-        self.compute_results(postings)
+    /// Performs a search to the specification of the given query, without sorting the results.
+    /// The order of the returned results is unspecified; this is useful for callers that will
+    /// sort the results by a key other than score.
+    pub fn query_unsorted(&self, query: &Query) -> Vec<SearchResult> {
+        let mut results = self.scored_results(query, &self.query_analyzer(), &mut ());
+        self.boost_recent(&mut results);
+        results
+    }
+
+    /// Performs a search to the specification of the given query, sending each result through
+    /// `tx` as soon as it's computed instead of collecting them all first, e.g. when exporting
+    /// all matches to a file. Sending blocks while the channel's buffer is full, so the results
+    /// should be received on another thread unless the buffer can hold all of them.
+    ///
+    /// Results are sent in no particular order; in particular, they aren't sorted by score.
+    /// Sending stops early if the receiving end of the channel is dropped.
+    ///
+    /// `Term`, `Match` and `Or` queries are merged lazily, straight from the index, so the
+    /// memory they use is bounded by the channel's buffer rather than by the number of matches.
+    /// Any other query, including one nested in an `Or`, has all of its matches collected before
+    /// they're sent.
+    pub fn query_stream<'a>(&'a self, query: &Query, tx: SyncSender<SearchResult<'a>>) {
+        match *query {
+            Term(_) | Match(_) | Or(_) => {
+                for (doc_id, positions) in self.lazy_postings(query) {
+                    let mut result = match self.result(doc_id, positions) {
+                        Some(result) => result,
+                        None => continue,
+                    };
+                    result.score += self.recency_boost(doc_id);
+                    if tx.send(result).is_err() {
+                        return;
+                    }
+                }
+            }
+            _ => {
+                for result in self.query_unsorted(query) {
+                    if tx.send(result).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Performs a search to the specification of the given query, sorting the results with the
+    /// given comparator over their documents instead of by score.
+    pub fn query_sorted_by<F>(&self, query: &Query, mut compare: F) -> Vec<SearchResult>
+        where F: FnMut(&Document, &Document) -> Ordering
+    {
+        let mut results = self.query_unsorted(query);
+        results.sort_by(|result1, result2| compare(result1.doc, result2.doc));
+        results
+    }
+
+    /// Performs a search to the specification of the given query, like `query`, returning owned
+    /// results whose documents' content is truncated to at most `max_content` bytes, at a char
+    /// boundary. Results are matched, scored and positioned using the full content; see
+    /// `SearchResult::to_owned_truncated`.
+    pub fn query_truncated(&self, query: &Query, max_content: usize) -> Vec<OwnedSearchResult> {
+        self.query(query).iter().map(|result| result.to_owned_truncated(max_content)).collect()
+    }
+
+    /// Performs a search to the specification of the given query, keeping only the results whose
+    /// documents have a timestamp between `start` and `end`, inclusive. Documents without a
+    /// timestamp are never returned.
+    pub fn query_time_range(&self, query: &Query, start: u64, end: u64) -> Vec<SearchResult> {
+        let mut results = self.query(query);
+        results.retain(|result| {
+            result.doc.timestamp.map_or(false, |timestamp| start <= timestamp && timestamp <= end)
+        });
+        results
+    }
+
+    /// Performs a search to the specification of the given query, also counting the matching
+    /// documents per facet value. `facet_of` maps each matching document to its facet value,
+    /// e.g. a category or language.
+    pub fn query_facets<F>(&self, query: &Query, facet_of: F)
+                           -> (Vec<SearchResult>, BTreeMap<String, usize>)
+        where F: Fn(&Document) -> &str
+    {
+        let results = self.query(query);
+        let mut facets = BTreeMap::new();
+        for result in &results {
+            *facets.entry(facet_of(result.doc).into()).or_insert(0) += 1;
+        }
+        (results, facets)
+    }
+
+    /// Performs a search to the specification of the given query, returning only the ids of the
+    /// matching documents, sorted by score in the same order as the results of `query`. The
+    /// matches are scored and sorted without constructing their search results.
+    pub fn query_ids(&self, query: &Query) -> Vec<usize> {
+        let mut ranked: Vec<_> = self.scored(query, &self.query_analyzer(), &mut ())
+                                     .into_iter()
+                                     .map(|(doc, _, score)| {
+                                         (score + self.recency_boost(doc.id),
+                                          self.sequence(doc.id),
+                                          doc.id)
+                                     })
+                                     .collect();
+        ranked.sort_by(|&(score1, sequence1, _), &(score2, sequence2, _)| {
+            compare_results((score1, sequence1), (score2, sequence2))
+        });
+        ranked.into_iter().map(|(_, _, doc_id)| doc_id).collect()
+    }
+
+    /// Returns the number of documents matching the query. This is cheaper than counting the
+    /// results of `query`, since the matching documents are never scored or sorted.
+    pub fn count(&self, query: &Query) -> usize {
+        self.query_rec(query).keys().filter(|doc_id| self.docs.contains_key(doc_id)).count()
+    }
+
+    /// Precomputes the postings of each of the given prefixes, so that `Prefix` and `PhrasePrefix`
+    /// queries for them, e.g. the short prefixes typed most often into an autocomplete box, don't
+    /// have to scan and merge the postings of every term starting with them. The precomputed
+    /// postings are discarded the next time a document is indexed or removed, as they'd be stale.
+    pub fn precompute_prefixes(&mut self, prefixes: &[&str]) {
+        for &prefix in prefixes {
+            let postings = self.prefix_uncached(prefix, &mut ());
+            self.prefix_cache.insert(prefix.into(), postings);
+        }
+    }
+
+    /// Returns the documents containing the given term at least `min_count` times, sorted by
+    /// score like the results of `query`. The term is looked up verbatim, like a `Term` query.
+    ///
+    /// Only occurrences of the term as a whole word are counted: since words are indexed under
+    /// each of their prefixes, counting every indexed occurrence would count "rustacean" as an
+    /// occurrence of "rust". When positions aren't tracked, no occurrences are recorded, so
+    /// nothing matches unless `min_count` is 0.
+    pub fn query_min_tf(&self, term: &str, min_count: usize) -> Vec<SearchResult> {
+        if min_count == 0 {
+            return self.query(&Term(term));
+        }
+        let postings = self.whole_words(term, &mut ())
+                           .unwrap_or_else(PostingsMap::new)
+                           .into_iter()
+                           .filter(|&(_, ref positions)| positions.len() >= min_count)
+                           .collect();
+        self.compute_results(postings)
+    }
+
+    /// Returns whether any document matches the query. This stops as soon as a match is found
+    /// where possible, and never scores or sorts the matching documents.
+    pub fn any_doc_matches(&self, query: &Query) -> bool {
+        match *query {
+            Match(query) => {
+                self.query_analyzer()
+                    .analyze(query)
+                    .into_iter()
+                    .map(Result::unwrap)
+                    .any(|token| self.term_matches_any_doc(&token.token))
+            }
+            Term(term) => self.term_matches_any_doc(term),
+            Or(queries) => queries.iter().any(|q| self.any_doc_matches(q)),
+            And(queries) => {
+                let postings: Vec<_> = queries.iter().map(|q| self.query_rec(q)).collect();
+                postings.intersection().any(|doc_id| self.docs.contains_key(doc_id))
+            }
+            _ => self.query_rec(query).keys().any(|doc_id| self.docs.contains_key(doc_id)),
+        }
+    }
+
+    /// Returns whether the postings of the given term reference any document in the index, which
+    /// is the first one unless the postings are out of sync with the documents.
+    fn term_matches_any_doc(&self, term: &str) -> bool {
+        self.index.get(term).map_or(false, |postings| {
+            postings.keys().any(|doc_id| self.docs.contains_key(doc_id))
+        })
+    }
+
+    /// A helper method for performing a Match query
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        self.query(&Match(query))
+    }
+
+    /// A helper method for performing a Match query, returning the id of each matching document
+    /// along with its content highlighted by `SearchResult::highlight`, sorted by score.
+    pub fn search_highlighted(&self,
+                              query: &str,
+                              before: &str,
+                              after: &str)
+                              -> Vec<(usize, String)> {
+        self.search(query)
+            .into_iter()
+            .map(|result| (result.doc.id, result.highlight(before, after)))
+            .collect()
+    }
+
+    /// A helper method for performing a Match query, returning only document ids
+    pub fn search_ids(&self, query: &str) -> Vec<usize> {
+        self.query_ids(&Match(query))
+    }
+
+    /// A helper method for searching for documents matching `include` but not `exclude`, both
+    /// performed as Match queries. The results' positions are those of the `include` terms only.
+    pub fn search_excluding(&self, include: &str, exclude: &str) -> Vec<SearchResult> {
+        let mut postings = self.query_rec(&Match(include));
+        for doc_id in self.query_rec(&Match(exclude)).keys() {
+            postings.remove(doc_id);
+        }
+        self.compute_results(postings)
+    }
+
+    /// Suggests a correction of a `Match` query that matches nothing, e.g. to offer "did you mean
+    /// beat?" for "beet". Each of the query's words is replaced by the closest word in the index,
+    /// by the index's fuzzy edit distance, within an edit distance of 2. Ties are broken in favor
+    /// of the word contained in more documents. Words without a close enough replacement are kept
+    /// as is.
+    ///
+    /// Returns `None` if the query already matches some document, or none of its words has a
+    /// close enough replacement.
+    pub fn did_you_mean(&self, query: &str) -> Option<String> {
+        const MAX_DISTANCE: usize = 2;
+        let words = self.phrase_terms(query, &self.query_analyzer());
+        if words.is_empty() || words.iter().any(|word| self.index.contains_key(word)) {
+            return None;
+        }
+        let mut corrected = false;
+        let suggestion: Vec<_> =
+            words.into_iter()
+                 .map(|word| {
+                     let len = word.chars().count();
+                     let closest = self.index
+                                       .iter()
+                                       .filter(|&(term, _)| {
+                                           let term_len = term.chars().count();
+                                           cmp::max(len, term_len) - cmp::min(len, term_len) <=
+                                           MAX_DISTANCE
+                                       })
+                                       .map(|(term, _)| {
+                                           (self.fuzzy_kind.distance(&word, term), term)
+                                       })
+                                       .filter(|&(distance, _)| distance <= MAX_DISTANCE)
+                                       .filter_map(|(distance, term)| {
+                                           // Indexed terms include prefixes of words; only
+                                           // suggest terms that are words.
+                                           self.whole_words(term, &mut ())
+                                               .map(|postings| (distance, postings.len(), term))
+                                       })
+                                       .min_by_key(|&(distance, doc_frequency, _)| {
+                                           (distance, usize::MAX - doc_frequency)
+                                       });
+                     match closest {
+                         Some((_, _, term)) => {
+                             corrected = true;
+                             term.clone()
+                         }
+                         None => word,
+                     }
+                 })
+                 .collect();
+        if corrected {
+            Some(suggestion.join(" "))
+        } else {
+            None
+        }
+    }
+
+    fn postings<A: Analyzer, M: Metrics>(&self, query: &str, analyzer: &A, metrics: &mut M)
+                                         -> PostingsMap {
+        metrics.merged();
+        analyzer.analyze(query)
+            .into_iter()
+            .map(Result::unwrap)
+            .unique()
+            .flat_map(|token| self.index.get(&token.token))
+            .inspect(|map| metrics.scanned(map))
+            .flat_map(|map| map)
+            .collect::<MergePostingsMap>()
+            .0
+
+    }
+
+    /// Looks up the postings of the given term, recording the lookup in `metrics`.
+    fn term<M: Metrics>(&self, term: &str, metrics: &mut M) -> Option<&PostingsMap> {
+        let postings = self.index.get(term);
+        if let Some(postings) = postings {
+            metrics.scanned(postings);
+        }
+        postings
+    }
+
+    fn phrase<A: Analyzer, M: Metrics>(&self, phrase: &str, analyzer: &A, metrics: &mut M)
+                                       -> PostingsMap {
+        let (terms, offsets) = self.phrase_tokens(phrase, analyzer);
+        let postings: Option<Vec<_>> = terms.iter()
+                                            .map(|term| self.whole_words(term, metrics))
+                                            .collect();
+        match postings {
+            Some(postings) => {
+                metrics.intersected();
+                intersect_consecutive(&postings.iter().collect::<Vec<_>>(), &offsets)
+            }
+            None => PostingsMap::new(),
+        }
+    }
+
+    fn phrase_prefix<A: Analyzer, M: Metrics>(&self, phrase: &str, analyzer: &A, metrics: &mut M)
+                                              -> PostingsMap {
+        let (mut terms, offsets) = self.phrase_tokens(phrase, analyzer);
+        let last = match terms.pop() {
+            Some(last) => self.prefix(&last, metrics),
+            None => return PostingsMap::new(),
+        };
+        if terms.is_empty() {
+            return last;
+        }
+        let postings: Option<Vec<_>> = terms.iter()
+                                            .map(|term| self.whole_words(term, metrics))
+                                            .chain(Some(Some(last)))
+                                            .collect();
+        match postings {
+            Some(postings) => {
+                metrics.intersected();
+                intersect_consecutive(&postings.iter().collect::<Vec<_>>(), &offsets)
+            }
+            None => PostingsMap::new(),
+        }
+    }
+
+    /// Returns the postings of the given term, keeping only the positions where the term is a
+    /// whole word rather than a prefix of a longer word. Returns `None` if there are no such
+    /// positions.
+    fn whole_words<M: Metrics>(&self, term: &str, metrics: &mut M) -> Option<PostingsMap> {
+        let postings = match self.term(term, metrics) {
+            Some(postings) => postings,
+            None => return None,
+        };
+        let whole_words: PostingsMap =
+            postings.iter()
+                    .filter_map(|(&doc_id, positions)| {
+                        let content = match self.docs.get(&doc_id) {
+                            Some(doc) => doc.content(),
+                            None => return None,
+                        };
+                        let positions: Vec<_> = positions.iter()
+                                                         .cloned()
+                                                         .filter(|position| {
+                                                             is_word_end(content,
+                                                                         position.offsets.1)
+                                                         })
+                                                         .collect();
+                        if positions.is_empty() {
+                            None
+                        } else {
+                            Some((doc_id, positions))
+                        }
+                    })
+                    .collect();
+        if whole_words.is_empty() {
+            None
+        } else {
+            Some(whole_words)
+        }
+    }
+
+    /// Returns, for each document matching the proximity query, the fewest words between the
+    /// query's terms in any of its matches, along with the positions of all of its matches.
+    fn near<A: Analyzer, M: Metrics>(&self,
+                                     phrase: &str,
+                                     slop: usize,
+                                     analyzer: &A,
+                                     metrics: &mut M)
+                                     -> BTreeMap<usize, (usize, Vec<Position>)> {
+        let terms = self.phrase_terms(phrase, analyzer);
+        if terms.is_empty() {
+            return BTreeMap::new();
+        }
+        let postings: Option<Vec<_>> = terms.iter()
+                                            .map(|term| self.whole_words(term, metrics))
+                                            .collect();
+        let postings = match postings {
+            Some(postings) => postings,
+            None => return BTreeMap::new(),
+        };
+        metrics.intersected();
+        let mut matches = BTreeMap::new();
+        for doc_id in (&postings[..]).intersection() {
+            let positions: Vec<_> = postings.iter().map(|posting| &posting[doc_id][..]).collect();
+            if let Some(near_match) = near_matches(&positions, slop) {
+                matches.insert(*doc_id, near_match);
+            }
+        }
+        matches
+    }
+
+    fn literal<A: Analyzer, M: Metrics>(&self, literal: &str, analyzer: &A, metrics: &mut M)
+                                        -> PostingsMap {
+        if literal.is_empty() {
+            return PostingsMap::new();
+        }
+        // The literal's first term may be the tail of a longer word in a matching document, but
+        // each of its other terms starts a word there, so it's indexed as one of the word's ngrams.
+        let terms = self.phrase_terms(literal, analyzer);
+        let candidates: Vec<usize> = if terms.len() > 1 {
+            let postings: Option<Vec<_>> = terms[1..]
+                                               .iter()
+                                               .map(|term| self.term(term, metrics))
+                                               .collect();
+            metrics.intersected();
+            match postings {
+                Some(postings) => (&postings[..]).intersection().cloned().collect(),
+                None => return PostingsMap::new(),
+            }
+        } else {
+            self.docs.keys().cloned().collect()
+        };
+        candidates.into_iter()
+                  .filter_map(|doc_id| {
+                      self.docs
+                          .get(&doc_id)
+                          .map(|doc| (doc_id, literal_positions(&doc.content, literal)))
+                  })
+                  .filter(|&(_, ref positions)| !positions.is_empty())
+                  .collect()
+    }
+
+    fn fuzzy<A: Analyzer, M: Metrics>(&self,
+                                      query: &str,
+                                      max_distance: usize,
+                                      analyzer: &A,
+                                      metrics: &mut M)
+                                      -> PostingsMap {
+        let words: Vec<_> = self.phrase_terms(query, analyzer)
+                                .into_iter()
+                                .map(|word| {
+                                    let len = word.chars().count();
+                                    (word, len)
+                                })
+                                .collect();
+        let is_match = |term: &str| {
+            let len = term.chars().count();
+            words.iter().any(|&(ref word, word_len)| {
+                cmp::max(len, word_len) - cmp::min(len, word_len) <= max_distance &&
+                self.fuzzy_kind.distance(word, term) <= max_distance
+            })
+        };
+        metrics.merged();
+        // Indexed terms include prefixes of words, so only count matching terms that are words.
+        self.index
+            .keys()
+            .filter(|term| is_match(term))
+            .filter_map(|term| self.whole_words(term, metrics))
+            .flat_map(|map| map)
+            .collect::<MergePostingsMap>()
+            .0
+    }
+
+    fn phrase_terms<A: Analyzer>(&self, phrase: &str, analyzer: &A) -> Vec<String> {
+        self.phrase_tokens(phrase, analyzer).0
+    }
+
+    /// Returns the terms of the phrase along with their positions relative to the first term,
+    /// which skip over any words the analyzer dropped, e.g. stop words.
+    fn phrase_tokens<A: Analyzer>(&self, phrase: &str, analyzer: &A) -> (Vec<String>, Vec<usize>) {
+        let tokens: Vec<_> = analyzer.analyze(phrase).into_iter().map(Result::unwrap).collect();
+        let first = tokens.first().map_or(0, |token| token.position.position);
+        tokens.into_iter()
+              .map(|token| (token.token, token.position.position - first))
+              .unzip()
+    }
+
+    fn prefix<M: Metrics>(&self, prefix: &str, metrics: &mut M) -> PostingsMap {
+        if let Some(postings) = self.prefix_cache.get(prefix) {
+            metrics.scanned(postings);
+            return postings.clone();
+        }
+        self.prefix_uncached(prefix, metrics)
+    }
+
+    fn prefix_uncached<M: Metrics>(&self, prefix: &str, metrics: &mut M) -> PostingsMap {
+        // Indexed terms never contain whitespace, so a blank prefix can't match anything.
+        if prefix.trim().is_empty() {
+            return PostingsMap::new();
+        }
+
+        let min = Included(prefix);
+        let max = prefix_upper_bound(prefix);
+        let max = match max {
+            Some(ref max) => Excluded(&max[..]),
+            None => Unbounded,
+        };
+        metrics.merged();
+        self.index
+            .range::<str, _>((min, max))
+            .map(|(_k, v)| v)
+            .inspect(|map| metrics.scanned(map))
+            .flat_map(|map| map)
+            .collect::<MergePostingsMap>()
+            .0
+
+    }
+
+    /// Returns the postings matching the query in ascending doc id order. The postings of `Or`
+    /// and `Match` queries are merged lazily; other queries are evaluated eagerly.
+    fn lazy_postings<'a>(&'a self, query: &Query) -> LazyPostings<'a> {
+        match *query {
+            Term(term) => Box::new(self.stored_postings(term)),
+            Match(query) => {
+                let analyzer = self.query_analyzer();
+                let terms: Vec<_> = self.phrase_terms(query, &analyzer)
+                                        .into_iter()
+                                        .unique()
+                                        .collect();
+                let postings = terms.iter().map(|term| self.stored_postings(term)).collect();
+                Box::new(MergePostings::new(postings))
+            }
+            Or(queries) => {
+                let postings = queries.iter().map(|q| self.lazy_postings(q)).collect();
+                Box::new(MergePostings::new(postings))
+            }
+            _ => Box::new(self.query_rec(query).into_iter()),
+        }
+    }
+
+    /// Returns an iterator over the stored postings of the given term, cloning each document's
+    /// positions only when it's reached.
+    fn stored_postings<'a>(&'a self, term: &str) -> LazyPostings<'a> {
+        Box::new(self.index
+                     .get(term)
+                     .into_iter()
+                     .flat_map(|postings| postings)
+                     .map(|(&doc_id, positions)| (doc_id, positions.clone())))
+    }
+
+    fn query_rec(&self, query: &Query) -> PostingsMap {
+        self.query_rec_with(query, &self.query_analyzer(), &mut ())
+    }
+
+    fn query_rec_with<A, M>(&self, query: &Query, analyzer: &A, metrics: &mut M) -> PostingsMap
+        where A: Analyzer,
+              M: Metrics
+    {
+        match *query {
+            Match(query) => self.postings(query, analyzer, metrics),
+            Term(term) => self.term(term, metrics).cloned().unwrap_or_else(PostingsMap::new),
+            And(queries) => {
+                let postings: Vec<_> = queries.iter()
+                                              .map(|q| self.query_rec_with(q, analyzer, metrics))
+                                              .collect();
+                metrics.intersected();
+                postings.intersect_postings()
+            }
+            Or(queries) => {
+                let postings: Vec<_> = queries.iter()
+                                              .map(|q| self.query_rec_with(q, analyzer, metrics))
+                                              .collect();
+                metrics.merged();
+                postings.into_iter().flat_map(|map| map).collect::<MergePostingsMap>().0
+            }
+            Phrase(phrase) => self.phrase(phrase, analyzer, metrics),
+            Prefix(prefix) => self.prefix(prefix, metrics),
+            PhrasePrefix(phrase) => self.phrase_prefix(phrase, analyzer, metrics),
+            Literal(literal) => self.literal(literal, analyzer, metrics),
+            Fuzzy(query, max_distance) => {
+                self.fuzzy(query, max_distance, analyzer, metrics)
+            }
+            Near(phrase, slop) => {
+                self.near(phrase, slop, analyzer, metrics)
+                    .into_iter()
+                    .map(|(doc_id, (_, positions))| (doc_id, positions))
+                    .collect()
+            }
+            Boolean { must, should, must_not } => {
+                self.boolean(must, should, must_not, analyzer, metrics)
+            }
+            NumericRange(min, max) => self.numeric_range(min, max),
+            Spanned(query) => {
+                let mut postings = self.query_rec_with(query, analyzer, metrics);
+                for (doc_id, positions) in &mut postings {
+                    if let Some(doc) = self.docs.get(doc_id) {
+                        *positions = word_spans(doc.content(), positions);
+                    }
+                }
+                postings
+            }
+        }
+    }
+
+    /// Returns the documents containing a number between `min` and `max`, inclusive, without
+    /// positions.
+    fn numeric_range(&self, min: i64, max: i64) -> PostingsMap {
+        if min > max {
+            return PostingsMap::new();
+        }
+        self.numbers
+            .range((Included(min), Included(max)))
+            .flat_map(|(_, doc_ids)| doc_ids)
+            .map(|&doc_id| (doc_id, vec![]))
+            .collect()
+    }
+
+    fn boolean<A: Analyzer, M: Metrics>(&self,
+                                        must: &[Query],
+                                        should: &[Query],
+                                        must_not: &[Query],
+                                        analyzer: &A,
+                                        metrics: &mut M)
+                                        -> PostingsMap {
+        let should: Vec<_> = should.iter()
+                                   .map(|q| self.query_rec_with(q, analyzer, metrics))
+                                   .collect();
+        let mut postings = if must.is_empty() {
+            metrics.merged();
+            should.into_iter().flat_map(|map| map).collect::<MergePostingsMap>().0
+        } else {
+            let must: Vec<_> = must.iter()
+                                   .map(|q| self.query_rec_with(q, analyzer, metrics))
+                                   .collect();
+            metrics.intersected();
+            let mut postings = must.intersect_postings();
+            for should_postings in &should {
+                for (doc_id, positions) in &mut postings {
+                    if let Some(should_positions) = should_postings.get(doc_id) {
+                        positions.merge_coalesce(should_positions.iter().cloned());
+                    }
+                }
+            }
+            postings
+        };
+        for q in must_not {
+            for doc_id in self.query_rec_with(q, analyzer, metrics).keys() {
+                postings.remove(doc_id);
+            }
+        }
+        postings
+    }
+
+    fn and_with_proximity<A: Analyzer, M: Metrics>(&self,
+                                                   queries: &[Query],
+                                                   analyzer: &A,
+                                                   metrics: &mut M)
+                                                   -> Vec<Scored> {
+        let postings: Vec<_> = queries.iter()
+                                      .map(|q| self.query_rec_with(q, analyzer, metrics))
+                                      .collect();
+        metrics.intersected();
+        metrics.matched();
+        let mut matches = self.score_postings(postings.intersect_postings());
+        for &mut (doc, _, ref mut score) in &mut matches {
+            let positions: Vec<_> = postings.iter()
+                                            .map(|posting| &posting[&doc.id][..])
+                                            .collect();
+            if let Some(span) = min_span(&positions) {
+                *score *= 1. + 1. / (1 + span) as f32;
+            }
+        }
+        matches
+    }
+
+    /// Scores the results of a proximity query so that tighter matches score higher.
+    fn near_ranked<A: Analyzer, M: Metrics>(&self,
+                                            phrase: &str,
+                                            slop: usize,
+                                            analyzer: &A,
+                                            metrics: &mut M)
+                                            -> Vec<Scored> {
+        let matches = self.near(phrase, slop, analyzer, metrics);
+        metrics.matched();
+        matches.into_iter()
+               .filter_map(|(doc_id, (gap, positions))| {
+                   self.score_match(doc_id, positions).map(|(doc, positions, score)| {
+                       (doc, positions, score / (1 + gap) as f32)
+                   })
+               })
+               .collect()
+    }
+
+    /// Scores the results of a prefix query so that matches closer to the whole prefix, i.e.
+    /// terms with less content following the prefix, score higher.
+    fn prefix_ranked<M: Metrics>(&self, prefix: &str, metrics: &mut M) -> Vec<Scored> {
+        let postings = self.prefix(prefix, metrics);
+        metrics.matched();
+        postings.into_iter()
+                .filter_map(|(doc_id, positions)| {
+                    self.docs.get(&doc_id).map(|doc| {
+                        let score = self.score_by(doc, &positions, |position| {
+                            let (begin, end) = position.offsets;
+                            let residual = (end - begin).saturating_sub(prefix.len());
+                            prefix.len() as f32 / (1 + residual) as f32
+                        });
+                        (doc, positions, score)
+                    })
+                })
+                .collect()
+    }
+
+    fn compute_results(&self, postings: PostingsMap) -> Vec<SearchResult> {
+        let mut results = self.compute_results_unsorted(postings);
+        self.rank(&mut results);
+        results
+    }
+
+    /// Applies the score boosts that don't depend on the query, then sorts the results.
+    fn rank(&self, results: &mut Vec<SearchResult>) {
+        self.boost_recent(results);
+        self.sort_results(results);
+    }
+
+    /// Sorts results by descending score, then in the order their documents were inserted, so
+    /// that the order of results with equal scores doesn't change as other documents are indexed
+    /// or removed.
+    fn sort_results(&self, results: &mut Vec<SearchResult>) {
+        results.sort_by(|result1, result2| {
+            compare_results((result1.score, self.sequence(result1.doc.id)),
+                            (result2.score, self.sequence(result2.doc.id)))
+        });
+    }
+
+    fn boost_recent(&self, results: &mut [SearchResult]) {
+        for result in results {
+            result.score += self.recency_boost(result.doc.id);
+        }
+    }
+
+    fn recency_boost(&self, doc_id: usize) -> f32 {
+        match self.docs.keys().next_back() {
+            Some(&newest) if self.recency_boost.0 != 0. && newest != 0 => {
+                self.recency_boost.0 * doc_id as f32 / newest as f32
+            }
+            _ => 0.,
+        }
+    }
+
+    fn compute_results_unsorted(&self, postings: PostingsMap) -> Vec<SearchResult> {
+        self.score_postings(postings).into_iter().map(into_result).collect()
+    }
+
+    fn score_postings(&self, postings: PostingsMap) -> Vec<Scored> {
+        postings.into_iter()
+                .filter_map(|(doc_id, positions)| self.score_match(doc_id, positions))
+                .collect()
+    }
+
+    /// Constructs the search result of the given document matching at the given positions,
+    /// scored according to the index's scoring mode.
+    ///
+    /// Returns `None` if the index doesn't contain the document, which would mean its postings are
+    /// out of sync with its documents; see `validate`.
+    fn result(&self, doc_id: usize, positions: Vec<Position>) -> Option<SearchResult> {
+        self.score_match(doc_id, positions).map(into_result)
+    }
+
+    /// Scores the given document matching at the given positions like `result`, without
+    /// constructing a search result.
+    fn score_match(&self, doc_id: usize, positions: Vec<Position>) -> Option<Scored> {
+        self.docs.get(&doc_id).map(|doc| {
+            let score = self.score(doc, &positions);
+            (doc, positions, score)
+        })
+    }
+
+    /// Returns the document's length norm, as precomputed when it was indexed.
+    fn doc_norm(&self, doc: &Document) -> f32 {
+        match self.length_norms.get(&doc.id) {
+            Some(&TotalF32(norm)) => norm,
+            None => self.scoring_mode.length_norm(doc.content.len()),
+        }
+    }
+
+    /// Computes the score of the document matching at the given positions, according to the
+    /// index's scoring mode and IDF weighting.
+    fn score(&self, doc: &Document, positions: &[Position]) -> f32 {
+        if !self.idf_weighting {
+            return self.scoring_mode.score_by(self.doc_norm(doc), positions);
+        }
+        self.score_by(doc, positions, |position| {
+            let (begin, end) = position.offsets;
+            (end - begin) as f32
+        })
+    }
+
+    /// Computes the score of the document matching at the given positions like `score`, given
+    /// the matched length that each position counts for before IDF weighting.
+    fn score_by<F>(&self, doc: &Document, positions: &[Position], matched_len: F) -> f32
+        where F: Fn(&Position) -> f32
+    {
+        let matched = positions.iter()
+                               .map(|position| {
+                                   let len = matched_len(position);
+                                   if !self.idf_weighting {
+                                       return len;
+                                   }
+                                   let (begin, end) = position.offsets;
+                                   len * self.idf(&self.span_term(&doc.content[begin..end]))
+                               })
+                               .sum::<f32>();
+        self.scoring_mode.normalize_by(self.doc_norm(doc), matched)
+    }
+
+    /// Returns the term that the given span of a document's content is indexed under, as analyzed
+    /// by the document analyzer, e.g. "dont" for "don't": the term spanning the whole span, or
+    /// the longest term in it if the span covers several words.
+    fn span_term(&self, span: &str) -> String {
+        let words = EnglishUtf8::from_bytes(span);
+        let terms = LowercaseFilter::with_locale(NgramsFilter::after_tokenizer(words), self.locale);
+        let mut longest = String::new();
+        for Token { token, position } in terms.into_iter().map(Result::unwrap) {
+            if position.offsets == (0, span.len()) {
+                return token;
+            }
+            if token.len() > longest.len() {
+                longest = token;
+            }
+        }
+        longest
+    }
+}
+
+/// A document matching a query at the given positions, with its score.
+type Scored<'a> = (&'a Document, Vec<Position>, f32);
+
+fn into_result((doc, positions, score): Scored) -> SearchResult {
+    SearchResult {
+        doc: doc,
+        positions: positions,
+        score: score,
+        terms: None,
+    }
+}
+
+/// Statistics about the documents of an index, as returned by `InvertedIndex::corpus_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CorpusStats {
+    /// The number of indexed documents.
+    pub num_docs: usize,
+    /// The summed length of the indexed documents' content, in bytes.
+    pub total_content_len: usize,
+}
+
+impl CorpusStats {
+    /// Returns the average length of the indexed documents' content, in bytes, or 0 if there are
+    /// no documents.
+    pub fn avg_content_len(&self) -> f32 {
+        if self.num_docs == 0 {
+            0.
+        } else {
+            self.total_content_len as f32 / self.num_docs as f32
+        }
+    }
+}
+
+/// Returns the combined vocabulary of several indexes, e.g. indexes holding different shards of a
+/// corpus: every term indexed by any of them, including the prefixes words are indexed under,
+/// mapped to its document frequency summed across the indexes.
+pub fn combined_vocabulary(indexes: &[&InvertedIndex]) -> BTreeMap<String, usize> {
+    let mut vocabulary = BTreeMap::new();
+    for index in indexes {
+        for (term, postings) in &index.index {
+            *vocabulary.entry(term.clone()).or_insert(0) += postings.len();
+        }
+    }
+    vocabulary
+}
+
+/// Metrics describing the work done to execute a query, as returned by
+/// `InvertedIndex::query_instrumented`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryMetrics {
+    /// The number of indexed terms whose postings were read.
+    pub terms_scanned: usize,
+    /// The number of documents in the postings that were read, counting a document once for each
+    /// term it was read for.
+    pub docs_touched: usize,
+    /// The number of intersections of postings computed, e.g. for `And` and `Phrase` queries.
+    pub intersections: usize,
+    /// The number of unions of postings computed, e.g. for `Or`, `Match` and `Prefix` queries.
+    pub merges: usize,
+    /// The time spent finding the matching documents.
+    pub matching_time: Duration,
+    /// The time spent scoring and sorting the matching documents.
+    pub ranking_time: Duration,
+}
+
+/// The analyzer used for queries by default, tokenizing them into their lowercased words.
+struct QueryAnalyzer {
+    locale: Locale,
+    stop_words: BTreeSet<String>,
+}
+
+impl Analyzer for QueryAnalyzer {
+    type Tokenizer = StopWordsFilter<LowercaseFilter<EnglishUtf8<io::Cursor<Vec<u8>>>>>;
+
+    fn analyze(&self, text: &str) -> Self::Tokenizer {
+        let words = LowercaseFilter::with_locale(EnglishUtf8::from_bytes(text), self.locale);
+        StopWordsFilter::after_tokenizer(words, self.stop_words.clone())
+    }
+}
+
+/// Records the work done to execute a query. The implementation for `()` records nothing, so that
+/// uninstrumented queries don't pay for instrumentation.
+trait Metrics {
+    /// Records that the given postings were read.
+    fn scanned(&mut self, postings: &PostingsMap);
+    /// Records that postings were intersected.
+    fn intersected(&mut self);
+    /// Records that postings were merged.
+    fn merged(&mut self);
+    /// Records that the matching documents have been found, and ranking has begun.
+    fn matched(&mut self);
+}
+
+impl Metrics for () {
+    fn scanned(&mut self, _: &PostingsMap) {}
+    fn intersected(&mut self) {}
+    fn merged(&mut self) {}
+    fn matched(&mut self) {}
+}
+
+struct Recorder {
+    metrics: QueryMetrics,
+    matched_at: Option<Instant>,
+}
+
+impl Metrics for Recorder {
+    fn scanned(&mut self, postings: &PostingsMap) {
+        self.metrics.terms_scanned += 1;
+        self.metrics.docs_touched += postings.len();
+    }
+
+    fn intersected(&mut self) {
+        self.metrics.intersections += 1;
+    }
+
+    fn merged(&mut self) {
+        self.metrics.merges += 1;
+    }
+
+    fn matched(&mut self) {
+        self.matched_at = Some(Instant::now());
+    }
+}
+
+/// Appends the leaves of the query tree, i.e. the queries other than `And`, `Or` and `Boolean`,
+/// to `leaves` from left to right. The `must_not` queries of `Boolean` queries are left out, since
+/// they never produce positions.
+fn collect_leaves<'a>(query: Query<'a>, leaves: &mut Vec<Query<'a>>) {
+    match query {
+        And(queries) | Or(queries) => {
+            for &query in queries {
+                collect_leaves(query, leaves);
+            }
+        }
+        Boolean { must, should, .. } => {
+            for &query in must.iter().chain(should) {
+                collect_leaves(query, leaves);
+            }
+        }
+        leaf => leaves.push(leaf),
+    }
+}
+
+/// Finds the matches of a proximity query in a document, given the positions of each of the
+/// query's terms in the document: runs of positions, one from each list, in increasing order,
+/// with at most `slop` tokens between them in total. Returns the fewest tokens between the terms
+/// of any match, along with the positions of all matches, or `None` if there are no matches.
+fn near_matches(positions: &[&[Position]], slop: usize) -> Option<(usize, Vec<Position>)> {
+    let (first, rest) = match positions.split_first() {
+        Some(split) => split,
+        None => return None,
+    };
+    let mut min_gap = None;
+    let mut matched = vec![];
+    'STARTS: for &start in first.iter() {
+        // Taking the earliest following occurrence of each term minimizes the gap for this start
+        let mut run = vec![start];
+        let mut gap = 0;
+        for list in rest {
+            let previous = run[run.len() - 1].position;
+            match list.iter().find(|position| position.position > previous) {
+                Some(&next) => {
+                    gap += next.position - previous - 1;
+                    run.push(next);
+                }
+                // Later starts can't be followed by this term either
+                None => break 'STARTS,
+            }
+        }
+        if gap <= slop {
+            min_gap = Some(min_gap.map_or(gap, |min_gap| cmp::min(min_gap, gap)));
+            for position in run {
+                matched.search_coalesce(0, position);
+            }
+        }
+    }
+    min_gap.map(|min_gap| (min_gap, matched))
+}
+
+/// Returns whether the positions refer to overlapping text of the same token.
+fn overlaps(position1: &Position, position2: &Position) -> bool {
+    position1.position == position2.position && position1.offsets.0 < position2.offsets.1 &&
+    position2.offsets.0 < position1.offsets.1
+}
+
+/// Returns whether a term ending at `offset` in `content` ends a word, i.e. no more word chars
+/// follow it before the next whitespace.
+fn is_word_end(content: &str, offset: usize) -> bool {
+    content[offset..].chars().take_while(|c| !c.is_whitespace()).all(|c| !c.is_alphanumeric())
+}
+
+/// Returns the numbers in the content, i.e. its whitespace-separated words that are integers
+/// fitting in an `i64` once surrounding punctuation, such as a currency sign or a full stop, is
+/// trimmed.
+fn numbers(content: &str) -> BTreeSet<i64> {
+    content.split(char::is_whitespace)
+           .map(|word| {
+               word.trim_left_matches(|c: char| !c.is_alphanumeric() && c != '-')
+                   .trim_right_matches(|c: char| !c.is_alphanumeric())
+           })
+           .filter_map(parse_number)
+           .collect()
+}
+
+/// Parses an integer with an optional minus sign and optional thousands separators, e.g. "-5" or
+/// "1,000". Anything else, such as the decimal "1.5" or the range "10-20", isn't a number.
+fn parse_number(word: &str) -> Option<i64> {
+    let digits = if word.starts_with('-') { &word[1..] } else { word };
+    let is_digits = |group: &str| !group.is_empty() && group.chars().all(|c| c.is_digit(10));
+    let mut groups = digits.split(',');
+    let first = groups.next().unwrap();
+    if !is_digits(first) {
+        return None;
+    }
+    let mut grouped = false;
+    for group in groups {
+        if group.len() != 3 || !is_digits(group) {
+            return None;
+        }
+        grouped = true;
+    }
+    if grouped && first.len() > 3 {
+        return None;
+    }
+    word.replace(",", "").parse().ok()
+}
+
+/// Returns the least string greater than every string starting with `prefix`: the prefix up to its
+/// last char that has a successor, with that char replaced by its successor. Trailing chars without
+/// a successor, i.e. `char::MAX`, are dropped rather than ending the range unbounded, so only
+/// prefixes made up entirely of `char::MAX` have no upper bound.
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut bound: String = prefix.into();
+    while let Some(last) = bound.pop() {
+        if let Some(next) = last.successor() {
+            bound.push(next);
+            return Some(bound);
+        }
+    }
+    None
+}
+
+/// Returns the positions widened to span the whole words they begin and end in, without
+/// duplicates.
+fn word_spans(content: &str, positions: &[Position]) -> Vec<Position> {
+    let mut spans: Vec<_> = positions.iter()
+                                     .map(|&position| {
+                                         let (begin, end) = position.offsets;
+                                         Position {
+                                             offsets: (word_start(content, begin),
+                                                       word_end(content, end)),
+                                             ..position
+                                         }
+                                     })
+                                     .collect();
+    spans.sort();
+    spans.dedup();
+    spans
+}
+
+/// Returns the offset in `content` of the start of the word containing the given offset, i.e. of
+/// the first word char after the previous whitespace, or `offset` if there is no such char or the
+/// offset is at whitespace.
+fn word_start(content: &str, offset: usize) -> usize {
+    if content[offset..].chars().next().map_or(true, char::is_whitespace) {
+        return offset;
+    }
+    content[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| !c.is_whitespace())
+        .filter(|&(_, c)| c.is_alphanumeric())
+        .last()
+        .map_or(offset, |(idx, _)| idx)
+}
+
+/// Returns the offset in `content` of the end of the word containing the given offset, i.e. just
+/// past the last word char before the next whitespace, or `offset` if there is no such char or the
+/// offset follows whitespace.
+fn word_end(content: &str, offset: usize) -> usize {
+    if content[..offset].chars().next_back().map_or(true, char::is_whitespace) {
+        return offset;
+    }
+    content[offset..]
+        .char_indices()
+        .take_while(|&(_, c)| !c.is_whitespace())
+        .filter(|&(_, c)| c.is_alphanumeric())
+        .last()
+        .map_or(offset, |(idx, c)| offset + idx + c.len_utf8())
+}
+
+/// Returns the positions of the occurrences of `literal` in `content`. Each position spans a whole
+/// occurrence and is numbered after the first token that ends inside it.
+fn literal_positions(content: &str, literal: &str) -> Vec<Position> {
+    let occurrences: Vec<_> = content.match_indices(literal).map(|(begin, _)| begin).collect();
+    if occurrences.is_empty() {
+        return vec![];
+    }
+    let tokens: Vec<_> = EnglishUtf8::from_bytes(content).into_iter().map(Result::unwrap).collect();
+    occurrences.into_iter()
+               .map(|begin| {
+                   let position = tokens.iter()
+                                        .position(|token| token.position.offsets.1 > begin)
+                                        .unwrap_or(tokens.len());
+                   Position::new((begin, begin + literal.len()), position)
+               })
+               .collect()
+}
+
+/// Returns the smallest distance between token positions that covers at least one position from
+/// each of the given sorted position lists, or `None` if any list is empty.
+fn min_span(positions: &[&[Position]]) -> Option<usize> {
+    if positions.is_empty() || positions.iter().any(|list| list.is_empty()) {
+        return None;
+    }
+    let mut heads = vec![0; positions.len()];
+    let mut min_span = usize::MAX;
+    loop {
+        let (mut min, mut max, mut min_idx) = (usize::MAX, 0, 0);
+        for (i, (list, &head)) in positions.iter().zip(&heads).enumerate() {
+            let position = match list.get(head) {
+                Some(position) => position.position,
+                None => return Some(min_span),
+            };
+            if position < min {
+                min = position;
+                min_idx = i;
+            }
+            if position > max {
+                max = position;
+            }
+        }
+        min_span = cmp::min(min_span, max - min);
+        heads[min_idx] += 1;
+    }
+}
+
+/// Returns the documents in which the postings occur in order at the given token positions relative
+/// to the first postings, e.g. consecutive positions, keeping only the positions that are part of
+/// such a run.
+fn intersect_consecutive(postings: &[&PostingsMap], offsets: &[usize]) -> PostingsMap {
+    let (first, rest) = match postings.split_first() {
+        Some(split) => split,
+        None => return PostingsMap::new(),
+    };
+    postings.intersection()
+            .filter_map(|doc_id| {
+                let mut positions = vec![];
+                for &start in &first[doc_id] {
+                    let run: Option<Vec<_>> =
+                        rest.iter()
+                            .enumerate()
+                            .map(|(i, posting)| {
+                                let candidates = &posting[doc_id];
+                                let position = start.position + offsets[i + 1];
+                                candidates.binary_search_by(|p| p.position.cmp(&position))
+                                          .ok()
+                                          .map(|idx| candidates[idx])
+                            })
+                            .collect();
+                    if let Some(run) = run {
+                        positions.search_coalesce(0, start);
+                        for position in run {
+                            positions.search_coalesce(0, position);
+                        }
+                    }
+                }
+                if positions.is_empty() {
+                    None
+                } else {
+                    Some((doc_id.clone(), positions))
+                }
+            })
+            .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use Query::*;
+    use CorpusStats;
+    use Document;
+    use FuzzyKind;
+    use IndexError;
+    use IndexMode;
+    use Inserted;
+    use LengthNorm;
+    use EnglishUtf8;
+    use InvertedIndex;
+    use Locale;
+    use Position;
+    use PostingsMap;
+    use ScoringMode;
+    use SearchResult;
+    use lowercase_ngrams;
+    use tokenize;
+    use rustc_serialize::json;
+    use std::cmp::Ordering;
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
+    use std::env;
+    use std::f32;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::mpsc;
+
+    #[test]
+    fn ngrams() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "learn to program in rust today");
+        let doc2 = Document::new(2, "what did you today do");
+        index.index(doc1.clone());
+        index.index(doc2.clone());
+        let search_results = index.search("to");
+        let expected: BTreeMap<_, _> =
+            [(doc1.id.clone(),
+              vec![Position::new((6, 8), 1), Position::new((25, 27), 5)]),
+             (doc2.id.clone(), vec![Position::new((13, 15), 3)])]
+                .iter()
+                .cloned()
+                .collect();
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[&search_result.doc.id])
+        }
+        assert_eq!("learn <span class=highlight>to</span> program in rust <span \
+                    class=highlight>to</span>day",
+                   search_results.iter()
+                                 .find(|search_result| search_result.doc.id == doc1.id)
+                                 .unwrap()
+                                 .highlight("<span class=highlight>", "</span>"));
+
+    }
+
+    #[test]
+    fn highlight() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(2,
+                                 "Won\u{2019}t this split the ecosystem? Will everyone use?");
+        index.index(doc1.clone());
+        let expected = "Won\u{2019}t this split the *e*cosystem? Will *e*veryone use?";
+        let search_results = index.search("e");
+        assert_eq!(1, search_results.len());
+        assert_eq!(search_results[0].highlight("*", "*"), expected);
+    }
+
+    #[test]
+    fn unicode() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "嗨, 您好");
+        index.index(doc.clone());
+        let to_search = "您";
+        let search_results = index.search(to_search);
+        let &SearchResult { ref doc, ref positions, .. } = search_results.iter().next().unwrap();
+        let Position{offsets:(begin, end), ..} = positions[0];
+        assert_eq!(&doc.content()[begin..end], to_search);
+    }
+
+    #[test]
+    fn update_doc() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "abc åäö");
+        index.index(doc);
+        let doc = Document::new(0, "different");
+        index.index(doc);
+        let search_results = index.search("å");
+        assert!(search_results.is_empty());
+        assert_eq!(index.docs.len(), 1);
+    }
+
+    #[test]
+    fn ranking() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "beat");
+        index.index(doc.clone());
+        let doc2 = Document::new(1, "beast");
+        index.index(doc2);
+        let search_results = index.search("be");
+        assert_eq!(index.docs.len(), 2);
+        // "beat" should be first, since it's a closer match
+        assert_eq!(search_results[0].doc.id, doc.id);
+    }
+
+    #[test]
+    fn duplicate_term() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "beat");
+        index.index(doc.clone());
+        let search_results = index.search("be be");
+        assert_eq!(search_results.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_term2() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "beat");
+        index.index(doc.clone());
+        let search_results = index.search("be b");
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+    }
+
+    #[test]
+    fn lowercase_search() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "BeAt");
+        index.index(doc.clone());
+        let search_results = index.search("bE");
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+    }
+
+    #[test]
+    fn lowercase_index() {
+        let mut index = InvertedIndex::new();
+        let doc = Document::new(0, "BeAt");
+        index.index(doc.clone());
+        let search_results = index.search("be");
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+    }
+
+    #[test]
+    fn and() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "learn to program in rust today");
+        let doc2 = Document::new(2, "what did you today do");
+        let doc3 = Document::new(3, "what did you do yesterday");
+        index.index(doc1.clone());
+        index.index(doc2.clone());
+        index.index(doc3.clone());
+        let search_results = index.query(&And(&[Match("today"), Match("you")]));
+        let expected: BTreeMap<_, _> = [(doc2,
+                                         vec![Position::new((9, 12), 2),
+                                              Position::new((13, 18), 3)])]
+                                           .iter()
+                                           .cloned()
+                                           .collect();
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[search_result.doc])
+        }
+    }
+
+    #[test]
+    fn and_or() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "learn to program in rust today");
+        let doc2 = Document::new(2, "what did you today do");
+        let doc3 = Document::new(3, "what did you do yesterday");
+        index.index(doc1.clone());
+        index.index(doc2.clone());
+        index.index(doc3.clone());
+        let search_results = index.query(&Or(&[Match("you"),
+                                               And(&[Match("today"), Match("you")])]));
+        let expected: BTreeMap<_, _> = [(doc2.id,
+                                         vec![Position::new((9, 12), 2),
+                                              Position::new((13, 18), 3)]),
+                                        (doc3.id, vec![Position::new((9, 12), 2)])]
+                                           .iter()
+                                           .cloned()
+                                           .collect();
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[&search_result.doc.id])
+        }
+    }
+
+    #[test]
+    fn phrase() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "learn to program in rust today");
+        index.index(doc1.clone());
+        let search_results = index.query(&Phrase("learn to program"));
+        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
+                                         vec![Position::new((0, 5), 0),
+                                              Position::new((6, 8), 1),
+                                              Position::new((9, 16), 2)])]
+                                           .iter()
+                                           .cloned()
+                                           .collect();
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
+        }
+        // Phrase terms must be whole words, not prefixes of the document's words
+        assert!(index.query(&Phrase("lear t pro")).is_empty());
+    }
+
+    #[test]
+    fn phrase2() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "is is is");
+        index.index(doc1.clone());
+        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
+                                         vec![Position::new((0, 2), 0),
+                                              Position::new((3, 5), 1),
+                                              Position::new((6, 8), 2)])]
+                                           .iter()
+                                           .cloned()
+                                           .collect();
+        assert!(index.query(&Phrase("i i")).is_empty());
+        let search_results = index.query(&Phrase("is is"));
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
+        }
+        // Each repeated word is matched once, however many runs of the phrase it's part of.
+        for phrase in &["is is is", "is  IS", "is, is"] {
+            let search_results = index.query(&Phrase(phrase));
+            assert_eq!(search_results.len(), 1);
+            assert_eq!(search_results[0].positions, expected[&doc1.id]);
+        }
+        assert!(index.query(&Phrase("is is is is")).is_empty());
+        index.index(Document::new(2, "is is is is is"));
+        let search_results = index.query(&Phrase("is is is"));
+        assert_eq!(search_results.len(), 2);
+        assert_eq!(search_results.iter().find(|result| result.doc.id == 2).unwrap().positions,
+                   [Position::new((0, 2), 0),
+                    Position::new((3, 5), 1),
+                    Position::new((6, 8), 2),
+                    Position::new((9, 11), 3),
+                    Position::new((12, 14), 4)]);
+    }
+
+    #[test]
+    fn prefix() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "is is is");
+        index.index(doc1.clone());
+        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
+                                         vec![Position::new((0, 2), 0),
+                                              Position::new((3, 5), 1),
+                                              Position::new((6, 8), 2)])]
+                                           .iter()
+                                           .cloned()
+                                           .collect();
+        let search_results = index.query(&Prefix("i"));
+        assert_eq!(search_results.len(), expected.len());
+        for search_result in &search_results {
+            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
+        }
+    }
+
+    #[test]
+    fn char_len_change() {
+        let mut index = InvertedIndex::new();
+        let s: String = "İİ".into();
+        let doc1 = Document::new(1, s.clone());
+        index.index(doc1.clone());
+        assert_eq!(index.index["i̇i̇"][&1][0].offsets.1, 4);
+    }
+
+    #[test]
+    fn query_unsorted() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "what did you today do"));
+        index.index(Document::new(3, "what did you do yesterday"));
+        let query = Or(&[Match("to"), Match("you")]);
+        let sorted: HashSet<_> = index.query(&query).iter().map(|result| result.doc.id).collect();
+        let unsorted: HashSet<_> = index.query_unsorted(&query)
+                                        .iter()
+                                        .map(|result| result.doc.id)
+                                        .collect();
+        assert_eq!(sorted.len(), 3);
+        assert_eq!(sorted, unsorted);
+    }
+
+    #[test]
+    fn query_facets() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust programming"));
+        index.index(Document::new(2, "python programming"));
+        index.index(Document::new(3, "programming in rust"));
+        index.index(Document::new(4, "cooking"));
+        let languages: BTreeMap<_, _> = [(1, "en"), (2, "en"), (3, "de"), (4, "en")]
+                                            .iter()
+                                            .cloned()
+                                            .collect();
+        let (results, facets) = index.query_facets(&Match("programming"),
+                                                   |doc| languages[&doc.id]);
+        assert_eq!(results.len(), 3);
+        let expected: BTreeMap<String, usize> = [("de".into(), 1), ("en".into(), 2)]
+                                                    .iter()
+                                                    .cloned()
+                                                    .collect();
+        assert_eq!(facets, expected);
+    }
+
+    #[test]
+    fn phrase_prefix() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "learn to program in rust today");
+        index.index(doc1.clone());
+        let search_results = index.query(&PhrasePrefix("learn to prog"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions,
+                   vec![Position::new((0, 5), 0),
+                        Position::new((6, 8), 1),
+                        Position::new((9, 16), 2)]);
+        assert!(index.query(&PhrasePrefix("learn to xyz")).is_empty());
+        assert!(index.query(&PhrasePrefix("to learn pro")).is_empty());
+    }
+
+    #[test]
+    fn max_positions_per_posting() {
+        let mut index = InvertedIndex::new();
+        index.set_max_positions_per_posting(Some(100));
+        let content = vec!["spam"; 10000].join(" ");
+        index.index(Document::new(1, content));
+        assert_eq!(index.index["spam"][&1].len(), 100);
+        assert_eq!(index.index["s"][&1].len(), 100);
+        let search_results = index.search("spam");
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions.len(), 100);
+    }
+
+    #[test]
+    fn query_ids() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "what did you today do"));
+        index.index(Document::new(3, "what did you do yesterday"));
+        index.index(Document::new(4, "today"));
+        let query = Or(&[Match("today"), Match("you")]);
+        let expected: Vec<_> = index.query(&query).iter().map(|result| result.doc.id).collect();
+        assert_eq!(expected.len(), 4);
+        assert_eq!(index.query_ids(&query), expected);
+        let expected: Vec<_> = index.search("to").iter().map(|result| result.doc.id).collect();
+        assert_eq!(index.search_ids("to"), expected);
+
+        index.set_and_proximity_boost(true);
+        index.set_recency_boost(0.05);
+        for query in &[Prefix("to"), Near("did today", 2), And(&[Match("did"), Match("do")])] {
+            let expected: Vec<_> = index.query(query).iter().map(|result| result.doc.id).collect();
+            assert!(!expected.is_empty());
+            assert_eq!(index.query_ids(query), expected);
+        }
+    }
+
+    #[test]
+    fn tied_scores_sorted_by_insertion_order() {
+        let mut index = InvertedIndex::new();
+        for &id in &[5, 3, 9, 1, 7] {
+            index.index(Document::new(id, "rust"));
+        }
+        for &id in &[8, 2, 6] {
+            index.index(Document::new(id, "rust rust"));
+        }
+        index.index(Document::new(4, "rust in rust"));
+        let ids: Vec<_> = index.search("rust").iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![8, 2, 6, 4, 5, 3, 9, 1, 7]);
+        assert_eq!(index.search_ids("rust"), ids);
+
+        // The order of the remaining tied results doesn't change as other docs come and go
+        index.remove(3);
+        index.index(Document::new(0, "rust"));
+        index.update_all(vec![Document::new(10, "rust"), Document::new(6, "rust rust")]);
+        let ids: Vec<_> = index.search("rust").iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![8, 2, 6, 4, 5, 9, 1, 7, 0, 10]);
+    }
+
+    #[test]
+    fn and_proximity_boost() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust aaaa bbbb fast"));
+        index.index(Document::new(2, "rust fast aaaa bbbb"));
+        let query = And(&[Match("rust"), Match("fast")]);
+        let ids: Vec<_> = index.query(&query).iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        index.set_and_proximity_boost(true);
+        let search_results = index.query(&query);
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert!(search_results[0].score > search_results[1].score);
+    }
+
+    #[test]
+    fn and_proximity_boost_without_positions() {
+        let mut index = InvertedIndex::new();
+        index.set_index_numbers(true);
+        index.set_and_proximity_boost(true);
+        index.index(Document::new(1, "book costs 45"));
+        let search_results = index.query(&And(&[NumericRange(0, 100), Match("book")]));
+        assert_eq!(search_results.len(), 1);
+        assert!(search_results[0].score.is_finite());
+
+        index.set_track_positions(false);
+        index.index(Document::new(2, "rust is fast"));
+        index.set_track_positions(true);
+        let search_results = index.query(&And(&[Match("rust"), Match("fast")]));
+        assert_eq!(search_results.len(), 1);
+        assert!(search_results[0].score.is_finite());
+    }
+
+    #[test]
+    fn prefix_ranking() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "beast"));
+        index.index(Document::new(2, "be"));
+        let search_results = index.query(&Prefix("be"));
+        assert_eq!(search_results.len(), 2);
+        assert_eq!(search_results[0].doc.id, 2);
+        assert!(search_results[0].score > search_results[1].score);
+    }
+
+    #[test]
+    fn ranked_queries_scored_consistently() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust aaaa bbbb fast, beast"));
+        index.index(Document::new(2, "rust fast aaaa bbbb, be"));
+        index.index(Document::new(3, "rusty and fast bees"));
+        index.set_and_proximity_boost(true);
+        index.set_idf_weighting(true);
+        let queries = [Prefix("be"),
+                       Prefix("rust"),
+                       Near("rust fast", 3),
+                       And(&[Match("rust"), Match("fast")])];
+        for query in &queries {
+            let results = index.query(query);
+            let scores = |results: Vec<SearchResult>| {
+                let mut scores: Vec<_> =
+                    results.iter().map(|result| (result.doc.id, result.score)).collect();
+                scores.sort_by_key(|&(doc_id, _)| doc_id);
+                scores
+            };
+            let ids: Vec<_> = results.iter().map(|result| result.doc.id).collect();
+            assert_eq!(index.query_ids(query), ids);
+            let (tx, rx) = mpsc::sync_channel(ids.len());
+            index.query_stream(query, tx);
+            assert_eq!(scores(rx.iter().collect()), scores(results.clone()));
+            assert_eq!(scores(index.query_unsorted(query)), scores(results));
+        }
+
+        let results = index.query(&Prefix("be"));
+        let result = results.iter().find(|result| result.doc.id == 1).unwrap();
+        let expected = 2. / 4. * index.idf("beast") / 26f32.sqrt();
+        assert_eq!(result.score, expected);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut reserved = InvertedIndex::new();
+        reserved.reserve(2);
+        let mut index = InvertedIndex::new();
+        for index in &mut [&mut reserved, &mut index] {
+            index.index(Document::new(1, "learn to program in rust today"));
+            index.index(Document::new(2, "what did you today do"));
+        }
+        assert_eq!(reserved, index);
+        assert_eq!(reserved.search_ids("today"), index.search_ids("today"));
+    }
+
+    #[test]
+    fn term_vector() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "To be, to"));
+        let expected: BTreeMap<String, usize> = [("b".into(), 1),
+                                                 ("be".into(), 1),
+                                                 ("t".into(), 2),
+                                                 ("to".into(), 2)]
+                                                    .iter()
+                                                    .cloned()
+                                                    .collect();
+        assert_eq!(index.term_vector(1), expected);
+        assert!(index.term_vector(2).is_empty());
+    }
+
+    #[test]
+    fn recency_boost() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1000, "rust news"));
+        index.index(Document::new(2000, "rust talk"));
+        assert_eq!(index.search_ids("rust"), vec![1000, 2000]);
+        index.set_recency_boost(0.1);
+        let search_results = index.search("rust");
+        assert_eq!(search_results[0].doc.id, 2000);
+        assert_eq!(search_results[1].doc.id, 1000);
+        assert!(search_results[0].score > search_results[1].score);
+        assert_eq!(index.search_ids("rust"), vec![2000, 1000]);
+    }
+
+    #[test]
+    fn index_eq_ord_hash() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust news"));
+        let mut boosted = index.clone();
+        boosted.set_recency_boost(0.1);
+        assert!(index != boosted);
+        assert!(index < boosted);
+        let indexes: HashSet<_> = vec![index.clone(), boosted.clone(), boosted]
+                                      .into_iter()
+                                      .collect();
+        assert_eq!(indexes.len(), 2);
+        assert!(indexes.contains(&index));
+    }
+
+    #[test]
+    fn index_eq_ignores_prefix_cache_and_batch() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust news"));
+        let mut cached = index.clone();
+        cached.precompute_prefixes(&["ru"]);
+        cached.begin_batch();
+        cached.index(Document::new(2, "rust today"));
+        assert_eq!(cached, index);
+        assert_eq!(cached.cmp(&index), Ordering::Equal);
+        let indexes: HashSet<_> = vec![index.clone(), cached.clone()].into_iter().collect();
+        assert_eq!(indexes.len(), 1);
+        let decoded: InvertedIndex = json::decode(&json::encode(&cached).unwrap()).unwrap();
+        assert!(decoded.prefix_cache.is_empty());
+        assert!(decoded.batch.is_none());
+        assert_eq!(decoded, index);
+    }
+
+    #[test]
+    fn term() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(0, "Beat"));
+        let search_results = index.query(&Term("be"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+        assert!(index.query(&Term("BE")).is_empty());
+        assert!(index.query(&Term("be at")).is_empty());
+        assert_eq!(index.query(&Match("BE")).len(), 1);
+    }
+
+    #[test]
+    fn phrase_scores_only_full_runs() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "rust to program in learn to");
+        let doc2 = Document::new(2, "we learn to program in rust");
+        index.index(doc1.clone());
+        index.index(doc2.clone());
+        let search_results = index.query(&Phrase("learn to program"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].doc, &doc2);
+        assert_eq!(search_results[0].positions,
+                   vec![Position::new((3, 8), 1),
+                        Position::new((9, 11), 2),
+                        Position::new((12, 19), 3)]);
+        let search_results = index.query(&Phrase("to"));
+        assert_eq!(search_results.len(), 2);
+    }
+
+    #[test]
+    fn any_doc_matches() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "what did you today do"));
+        assert!(index.any_doc_matches(&And(&[Match("today"), Match("you")])));
+        assert!(!index.any_doc_matches(&And(&[Match("rust"), Match("you")])));
+        assert!(index.any_doc_matches(&Or(&[Match("nothing"), Match("rust")])));
+        assert!(!index.any_doc_matches(&Or(&[Match("nothing"), Match("nowhere")])));
+        assert!(index.any_doc_matches(&Phrase("you today")));
+        assert!(!index.any_doc_matches(&Phrase("today you")));
+        assert!(!index.any_doc_matches(&And(&[])));
+    }
+
+    #[test]
+    fn long_phrases() {
+        let mut index = InvertedIndex::new();
+        let doc1 = Document::new(1, "to be or not to be");
+        let doc2 = Document::new(2, "not to be or maybe, or not");
+        index.index(doc1.clone());
+        index.index(doc2.clone());
+        let search_results = index.query(&Phrase("or not to be"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].doc, &doc1);
+        assert_eq!(search_results[0].positions,
+                   vec![Position::new((6, 8), 2),
+                        Position::new((9, 12), 3),
+                        Position::new((13, 15), 4),
+                        Position::new((16, 18), 5)]);
+        let search_results = index.query(&Phrase("not to be"));
+        assert_eq!(search_results.len(), 2);
+        for search_result in &search_results {
+            let expected = if search_result.doc == &doc1 {
+                vec![Position::new((9, 12), 3),
+                     Position::new((13, 15), 4),
+                     Position::new((16, 18), 5)]
+            } else {
+                vec![Position::new((0, 3), 0), Position::new((4, 6), 1), Position::new((7, 9), 2)]
+            };
+            assert_eq!(search_result.positions, expected);
+        }
+        // "be or" and "or not" both occur in doc2, but never as "be or not".
+        let search_results = index.query(&Phrase("be or not"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].doc, &doc1);
+        assert_eq!(search_results[0].positions,
+                   vec![Position::new((3, 5), 1),
+                        Position::new((6, 8), 2),
+                        Position::new((9, 12), 3)]);
+    }
+
+    #[test]
+    fn idf() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust is fast"));
+        index.index(Document::new(2, "rust is safe"));
+        index.index(Document::new(3, "python is easy"));
+        assert_eq!(index.doc_frequency("is"), 3);
+        assert_eq!(index.doc_frequency("rust"), 2);
+        assert_eq!(index.doc_frequency("safe"), 1);
+        assert_eq!(index.doc_frequency("java"), 0);
+        assert!(index.idf("safe") > index.idf("rust"));
+        assert!(index.idf("rust") > index.idf("is"));
+        assert!(index.idf("java") > index.idf("safe"));
+        assert!((index.idf("is") - (1f32 + 0.5 / 3.5).ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn remove() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "to be or not to be"));
+        index.index(Document::new(2, "today"));
+        assert_eq!(index.remove(1), Some(Document::new(1, "to be or not to be")));
+        assert_eq!(index.remove(1), None);
+        assert!(index.search("be").is_empty());
+        assert_eq!(index.search_ids("to"), vec![2]);
+        index.index(Document::new(2, "to to"));
+        index.index(Document::new(2, "tomorrow"));
+        assert!(index.remove(2).is_some());
+        assert!(index.index.is_empty());
+        assert!(index.docs.is_empty());
+    }
+
+    /// Returns a path for a test's log that's unique to the test and the test process.
+    fn log_path(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("inverted_index_{}_{}.jsonl", process::id(), test))
+    }
+
+    #[test]
+    fn log() {
+        let path = log_path("log");
+        let _ = fs::remove_file(&path);
+        let expected = {
+            let mut index = InvertedIndex::open_with_log(&path).unwrap();
+            index.index(Document::new(1, "learn to program in rust today"));
+            index.index(Document::new(2, "what did you today do"));
+            index.index(Document::new(3, "what did you do yesterday"));
+            index.index(Document::new(1, "learn rust"));
+            index.remove(2);
+            index
+        };
+        let mut index = InvertedIndex::open_with_log(&path).unwrap();
+        assert_eq!(index, expected);
+        assert_eq!(index.search_ids("rust"), vec![1]);
+        index.compact().unwrap();
+        let num_ops = BufReader::new(File::open(&path).unwrap()).lines().count();
+        assert_eq!(num_ops, 2);
+        let reopened = InvertedIndex::open_with_log(&path).unwrap();
+        assert_eq!(reopened, index);
+        assert_eq!(reopened.docs, expected.docs);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_torn_entry() {
+        let path = log_path("log_torn_entry");
+        let _ = fs::remove_file(&path);
+        {
+            let mut index = InvertedIndex::open_with_log(&path).unwrap();
+            index.index(Document::new(1, "learn rust"));
+        }
+        let valid_len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"variant\":").unwrap();
+        {
+            let mut index = InvertedIndex::open_with_log(&path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+            index.index(Document::new(2, "learn python"));
+        }
+        let index = InvertedIndex::open_with_log(&path).unwrap();
+        assert_eq!(index.search_ids("learn"), vec![1, 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_settings() {
+        let path = log_path("log_settings");
+        let _ = fs::remove_file(&path);
+        let stop_words: BTreeSet<_> = vec!["the".to_string()].into_iter().collect();
+        let expected = {
+            let mut index = InvertedIndex::new();
+            index.set_stop_words(stop_words.clone());
+            index.set_index_numbers(true);
+            let mut index = index.with_log(&path).unwrap();
+            index.index(Document::new(1, "the rust book costs 45"));
+            index
+        };
+        let mut index = InvertedIndex::new();
+        index.set_stop_words(stop_words);
+        index.set_index_numbers(true);
+        let index = index.with_log(&path).unwrap();
+        assert_eq!(index, expected);
+        assert!(!index.contains_term("the"));
+        assert_eq!(index.query_ids(&NumericRange(40, 50)), [1]);
+
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust"));
+        assert_eq!(index.with_log(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_clone_isolated() {
+        let path = log_path("log_clone_isolated");
+        let _ = fs::remove_file(&path);
+        let mut index = InvertedIndex::open_with_log(&path).unwrap();
+        index.index(Document::new(1, "learn rust"));
+        let mut cloned = index.clone();
+        cloned.index(Document::new(2, "rust today"));
+        let mut decoded: InvertedIndex = json::decode(&json::encode(&index).unwrap()).unwrap();
+        decoded.index(Document::new(3, "rust news"));
+        let reopened = InvertedIndex::open_with_log(&path).unwrap();
+        assert_eq!(reopened, index);
+        assert_eq!(reopened.docs.keys().cloned().collect::<Vec<_>>(), [1]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn log_append_error() {
+        let path = log_path("log_append_error").join("missing_dir").join("log.jsonl");
+        let mut index = InvertedIndex::open_with_log(&path).unwrap();
+        match index.try_index(Document::new(1, "rust")) {
+            Err(IndexError::Log { kind, .. }) => assert_eq!(kind, io::ErrorKind::NotFound),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(index.docs.is_empty());
+        assert_eq!(index.try_remove(1), Ok(None));
+        let docs = vec![Document::new(1, "rust"), Document::new(2, "today")];
+        match index.update_all_checked(docs) {
+            Err(IndexError::Log { kind, .. }) => assert_eq!(kind, io::ErrorKind::NotFound),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(index, InvertedIndex::new());
+    }
+
+    #[test]
+    fn query_sorted_by() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust is safe"));
+        index.index(Document::new(2, "a rust tutorial"));
+        index.index(Document::new(3, "python"));
+        index.index(Document::new(4, "learn rust"));
+        let search_results = index.query_sorted_by(&Match("rust"),
+                                                   |doc1, doc2| doc1.content.cmp(&doc2.content));
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![2, 4, 1]);
+    }
+
+    #[test]
+    fn prefix_edge_cases() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "嗨, 您好"));
+        index.index(Document::new(2, "您"));
+        let search_results = index.query(&Prefix("您"));
+        assert_eq!(search_results.len(), 2);
+        for search_result in &search_results {
+            assert_eq!(search_result.positions.len(), 1);
+            let Position { offsets: (begin, end), .. } = search_result.positions[0];
+            let expected = if search_result.doc.id == 1 { "您好" } else { "您" };
+            assert_eq!(&search_result.doc.content[begin..end], expected);
+        }
+        assert!(index.query(&Prefix("")).is_empty());
+        assert!(index.query(&Prefix("  ")).is_empty());
+        assert!(index.query(&Prefix("\t\n")).is_empty());
+    }
+
+    #[test]
+    fn prefix_max_char_edge_case() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "zz"));
+        let postings: PostingsMap = vec![(1, vec![Position::new((0, 1), 0)])].into_iter().collect();
+        index.index.insert("\u{10FFFF}".into(), postings.clone());
+        index.index.insert("\u{10FFFF}a".into(), postings.clone());
+        assert_eq!(index.prefix("\u{10FFFF}", &mut ()), postings);
+        assert_eq!(index.prefix("z", &mut ())[&1], vec![Position::new((0, 2), 0)]);
+    }
+
+    #[test]
+    fn prefix_upper_bound() {
+        assert_eq!(super::prefix_upper_bound("ab"), Some("ac".into()));
+        assert_eq!(super::prefix_upper_bound("a\u{10FFFF}"), Some("b".into()));
+        assert_eq!(super::prefix_upper_bound("a\u{10FFFF}\u{10FFFF}"), Some("b".into()));
+        assert_eq!(super::prefix_upper_bound("\u{D7FF}"), Some("\u{E000}".into()));
+        assert_eq!(super::prefix_upper_bound("\u{10FFFF}"), None);
+
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "b bb c"));
+        let postings: PostingsMap = vec![(1, vec![Position::new((0, 1), 0)])].into_iter().collect();
+        index.index.insert("a\u{10FFFF}".into(), postings.clone());
+        index.index.insert("a\u{10FFFF}z".into(), postings.clone());
+        let (results, metrics) = index.query_instrumented(&Prefix("a\u{10FFFF}"));
+        assert_eq!(results.len(), 1);
+        // The terms starting with "b" and "c" aren't scanned
+        assert_eq!(metrics.terms_scanned, 2);
+    }
+
+    #[test]
+    fn query_stream() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "what did you today do"));
+        index.index(Document::new(3, "what did you do yesterday"));
+        index.index(Document::new(4, "nothing to see here"));
+        let query = Or(&[Match("today"), Match("you")]);
+        let (tx, rx) = mpsc::sync_channel(3);
+        index.query_stream(&query, tx);
+        let mut streamed: Vec<_> = rx.iter().map(|result| (result.doc.id, result.score)).collect();
+        streamed.sort_by(|&(id1, _), &(id2, _)| id1.cmp(&id2));
+        let mut expected: Vec<_> = index.query(&query)
+                                        .iter()
+                                        .map(|result| (result.doc.id, result.score))
+                                        .collect();
+        expected.sort_by(|&(id1, _), &(id2, _)| id1.cmp(&id2));
+        assert_eq!(streamed.len(), 3);
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    fn turkish_locale() {
+        let mut index = InvertedIndex::new();
+        index.set_locale(Locale::Turkish);
+        index.index(Document::new(1, "İstanbul"));
+        index.index(Document::new(2, "Istanbul"));
+        assert_eq!(index.search_ids("istanbul"), vec![1]);
+        assert_eq!(index.search_ids("İSTANBUL"), vec![1]);
+        assert_eq!(index.search_ids("ıstanbul"), vec![2]);
+        assert_eq!(index.search_ids("ISTANBUL"), vec![2]);
+
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "Istanbul"));
+        assert_eq!(index.search_ids("istanbul"), vec![1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "the stop words can't be changed once documents are indexed")]
+    fn set_stop_words_after_indexing() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "the rust book"));
+        index.set_stop_words(["the".into()].iter().cloned().collect());
+    }
+
+    #[test]
+    #[should_panic(expected = "the recency boost must be finite")]
+    fn set_recency_boost_nan() {
+        let mut index = InvertedIndex::new();
+        index.set_recency_boost(f32::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "the locale can't be changed once documents are indexed")]
+    fn set_locale_after_indexing() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "ILIK"));
+        index.set_locale(Locale::Turkish);
+    }
+
+    #[test]
+    fn literal() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "rust, today!"));
+        index.index(Document::new(3, "today rust"));
+        index.index(Document::new(4, "Rust Today"));
+        index.index(Document::new(5, "trust today and rust today"));
+        let search_results = index.query(&Literal("rust today"));
+        let mut ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 5]);
+        for search_result in &search_results {
+            for &Position { offsets: (begin, end), .. } in &search_result.positions {
+                assert_eq!(&search_result.doc.content[begin..end], "rust today");
+            }
+        }
+        let trust = search_results.iter().find(|result| result.doc.id == 5).unwrap();
+        assert_eq!(trust.positions,
+                   vec![Position::new((1, 11), 0), Position::new((16, 26), 3)]);
+
+        assert_eq!(index.query_ids(&Literal(", today!")), vec![2]);
+    }
+
+    #[test]
+    fn query_instrumented() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "programming in rust"));
+        index.index(Document::new(3, "rust"));
+        let query = And(&[Match("rust"), Prefix("prog")]);
+        let (results, metrics) = index.query_instrumented(&query);
+        let ids: Vec<_> = results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, index.query_ids(&query));
+        assert_eq!(ids.len(), 2);
+        // "rust" is read for Match, then "prog" through "program" and "programm" through
+        // "programming" for Prefix.
+        assert_eq!(metrics.terms_scanned, 9);
+        assert_eq!(metrics.docs_touched, 3 + 4 * 2 + 4 * 1);
+        assert_eq!(metrics.intersections, 1);
+        assert_eq!(metrics.merges, 2);
+    }
+
+    #[test]
+    fn highlight_doc() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "rust"));
+        assert_eq!(index.highlight_doc(1, &["Rust", "prog"], "<b>", "</b>"),
+                   Some("learn to <b>prog</b>ram in <b>rust</b> today".into()));
+        assert_eq!(index.highlight_doc(1, &["to learn", "python"], "<", ">"),
+                   Some("<learn> <to> program in rust <to>day".into()));
+        assert_eq!(index.highlight_doc(3, &["rust"], "<b>", "</b>"), None);
+    }
+
+    #[test]
+    fn untracked_positions() {
+        let mut index = InvertedIndex::new();
+        index.set_track_positions(false);
+        index.set_and_proximity_boost(true);
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "rust rust rust"));
+        index.index(Document::new(3, "what did you do yesterday"));
+        assert!(index.index.values().flat_map(|postings| postings.values()).all(Vec::is_empty));
+
+        assert_eq!(index.search_ids("rust"), vec![1, 2]);
+        assert_eq!(index.search_ids("prog YOU"), vec![1, 3]);
+        assert_eq!(index.query_ids(&And(&[Match("rust"), Match("today")])), vec![1]);
+        assert_eq!(index.query_ids(&Prefix("ye")), vec![3]);
+        assert!(index.query(&Phrase("rust today")).is_empty());
+        assert!(index.query(&PhrasePrefix("rust to")).is_empty());
+
+        let search_results = index.search("rust");
+        assert_eq!(search_results[1].highlight("<b>", "</b>"), "rust rust rust");
+        assert_eq!(search_results[1].score, 0.);
+    }
+
+    #[test]
+    fn update_all() {
+        let mut sequential = InvertedIndex::new();
+        for id in 0..15 {
+            sequential.index(Document::new(id, format!("doc {} is about rust and r{}", id, id)));
+        }
+        let mut batched = sequential.clone();
+        let updates: Vec<_> = (5..15)
+                                  .map(|id| {
+                                      Document::new(id, format!("doc {} was about python", id % 3))
+                                  })
+                                  .chain(Some(Document::new(20, "a new doc")))
+                                  .chain(Some(Document::new(7, "doc 7 updated twice")))
+                                  .collect();
+        for doc in updates.clone() {
+            sequential.index(doc);
+        }
+        batched.update_all(updates);
+        assert_eq!(batched, sequential);
+        assert!(!batched.index.contains_key("r14"));
+        assert_eq!(batched.search_ids("python").len(), 9);
+    }
+
+    #[test]
+    fn phrase_whole_words() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rusty today"));
+        index.index(Document::new(2, "rust todays"));
+        index.index(Document::new(3, "Rust, today!"));
+        index.index(Document::new(4, "they trust today's rust"));
+        assert_eq!(index.query_ids(&Phrase("rust today")), vec![3]);
+        assert_eq!(index.query_ids(&Phrase("rusty")), vec![1]);
+        assert_eq!(index.query_ids(&PhrasePrefix("rust tod")), vec![2, 3]);
+        assert!(index.query(&PhrasePrefix("rus today")).is_empty());
+    }
+
+    #[test]
+    fn whole_words_with_punctuation() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "I don't know"));
+        index.index(Document::new(2, "send an e-mail to the U.S. office"));
+        assert_eq!(index.query_ids(&Phrase("don't know")), vec![1]);
+        assert_eq!(index.query_ids(&Near("don't know", 1)), vec![1]);
+        assert_eq!(index.query_ids(&Phrase("e-mail")), vec![2]);
+        assert_eq!(index.query_ids(&Phrase("U.S. office")), vec![2]);
+        assert!(index.query(&Phrase("do know")).is_empty());
+    }
+
+    #[test]
+    fn query_normalized() {
+        let mut index = InvertedIndex::new();
+        assert!(index.query_normalized(&Match("rust")).is_empty());
+        index.index(Document::new(1, "learn to program in rust today"));
+        let search_results = index.query_normalized(&Match("rust"));
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].score, 1.);
+
+        index.index(Document::new(2, "rust"));
+        index.index(Document::new(3, "rust, rust and more rust"));
+        let search_results = index.query_normalized(&Match("rust"));
+        let unnormalized = index.query(&Match("rust"));
+        assert_eq!(search_results.len(), 3);
+        assert_eq!(search_results[0].score, 1.);
+        for (result, unnormalized) in search_results.iter().zip(&unnormalized) {
+            assert_eq!(result.doc.id, unnormalized.doc.id);
+            assert!(result.score > 0. && result.score <= 1.);
+        }
+        assert!(search_results[2].score < 1.);
+    }
+
+    #[test]
+    fn fuzzy() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "the rust book"));
+        index.index(Document::new(2, "then there was rusty"));
+        index.index(Document::new(3, "teh end"));
+        assert_eq!(index.query_ids(&Fuzzy("teh", 1)), vec![3]);
+        assert_eq!(index.query_ids(&Fuzzy("teh", 2)), vec![3, 2, 1]);
+        assert_eq!(index.query_ids(&Fuzzy("RUSTT", 1)), vec![2, 1]);
+        assert!(index.query(&Fuzzy("ru", 1)).is_empty());
+
+        index.set_fuzzy_kind(FuzzyKind::Damerau);
+        assert_eq!(index.query_ids(&Fuzzy("teh", 1)), vec![3, 1]);
+        let search_results = index.query(&Fuzzy("teh", 1));
+        assert_eq!(search_results[1].positions, vec![Position::new((0, 3), 0)]);
+    }
+
+    #[test]
+    fn max_total_postings_per_doc() {
+        let mut index = InvertedIndex::new();
+        index.set_max_total_postings_per_doc(Some(10));
+        // "rust" and "today" expand to 4 + 5 ngrams
+        assert_eq!(index.try_index(Document::new(1, "rust today")), Ok(()));
+        let err = index.try_index(Document::new(2, "supercalifragilistic")).unwrap_err();
+        assert_eq!(err,
+                   IndexError::TooManyPostings {
+                       doc_id: 2,
+                       postings: 20,
+                       max: 10,
+                   });
+        assert!(err.to_string().contains("document 2"));
+        assert_eq!(index.search_ids("super"), vec![]);
+        assert!(index.docs.get(&2).is_none());
+
+        // A failed update leaves the previous version in place
+        assert!(index.try_index(Document::new(1, "programming in rust")).is_err());
+        assert_eq!(index.search_ids("today"), vec![1]);
+
+        // A failed batch update leaves every document as it was
+        let unchanged = index.clone();
+        let docs = vec![Document::new(3, "go"), Document::new(4, "supercalifragilistic")];
+        assert_eq!(index.update_all_checked(docs),
+                   Err(IndexError::TooManyPostings {
+                       doc_id: 4,
+                       postings: 20,
+                       max: 10,
+                   }));
+        assert_eq!(index, unchanged);
+    }
+
+    #[test]
+    fn query_stream_or() {
+        let mut index = InvertedIndex::new();
+        for id in 0..20 {
+            index.index(Document::new(id, format!("doc{} rust r{} today t{}", id % 3, id, id % 4)));
+        }
+        let query = Or(&[Match("doc1 t2"),
+                         Term("r1"),
+                         Or(&[Match("TODAY"), Prefix("doc2")]),
+                         Phrase("rust r1")]);
+        let (tx, rx) = mpsc::sync_channel(20);
+        index.query_stream(&query, tx);
+        let streamed: Vec<_> = rx.iter().map(|result| (result.doc.id, result.positions)).collect();
+        let eager: Vec<_> = index.query_rec(&query).into_iter().collect();
+        assert_eq!(streamed.len(), 20);
+        assert_eq!(streamed, eager);
+    }
+
+    #[test]
+    fn common_term() {
+        let mut index = InvertedIndex::new();
+        for id in 0..1000 {
+            index.index(Document::new(id, format!("entry {} mentions rust once", id)));
+        }
+        let search_results = index.search("rust");
+        assert_eq!(search_results.len(), 1000);
+        let ids: HashSet<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids.len(), 1000);
+        for search_result in &search_results {
+            let (id, content) = (search_result.doc.id, search_result.doc.content());
+            let begin = content.find("rust").unwrap();
+            assert_eq!(search_result.positions, vec![Position::new((begin, begin + 4), 3)]);
+            assert!(search_result.score > 0.);
+            assert!(search_result.score.is_finite());
+            assert_eq!(search_result.score, 4. / (content.len() as f32).sqrt(), "doc {}", id);
+        }
+        for pair in search_results.windows(2) {
+            assert!(pair[0].score > pair[1].score ||
+                    pair[0].score == pair[1].score && pair[0].doc.id < pair[1].doc.id);
+        }
+        // Shorter documents score higher
+        assert_eq!(search_results[0].doc.id, 0);
+        assert_eq!(search_results[999].doc.id, 999);
+        assert_eq!(index.search_ids("rust"),
+                   search_results.iter().map(|result| result.doc.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn query_with_terms() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "today, rust is rusty"));
+        index.index(Document::new(3, "nothing here"));
+        let search_results = index.query_with_terms(&Or(&[Match("rust"), Match("today")]));
+        assert_eq!(search_results.len(), 2);
+        for search_result in &search_results {
+            let terms = search_result.terms.as_ref().unwrap();
+            assert_eq!(terms.len(), search_result.positions.len());
+            for (position, &term) in search_result.positions.iter().zip(terms) {
+                let (begin, end) = position.offsets;
+                let expected = if term == 0 { "rust" } else { "today" };
+                assert_eq!(&search_result.doc.content[begin..end], expected);
+            }
+        }
+        let doc2 = search_results.iter().find(|result| result.doc.id == 2).unwrap();
+        assert_eq!(doc2.terms, Some(vec![1, 0, 0]));
+        assert!(index.search("rust").iter().all(|result| result.terms.is_none()));
+    }
+
+    #[test]
+    fn count() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "what did you today do"));
+        index.index(Document::new(3, "what did you do yesterday"));
+        index.index(Document::new(4, "rust today"));
+        let queries = [Match("today"),
+                       Match("nothing"),
+                       Match("to you"),
+                       And(&[Match("rust"), Match("today")]),
+                       Or(&[Match("rust"), Match("yesterday")]),
+                       Phrase("rust today"),
+                       Phrase("today rust")];
+        let counts: Vec<_> = queries.iter().map(|query| index.count(query)).collect();
+        assert_eq!(counts, vec![3, 0, 4, 2, 3, 2, 0]);
+        for query in &queries {
+            assert_eq!(index.count(query), index.query(query).len());
+        }
+    }
+
+    #[test]
+    fn boolean() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust"));
+        index.index(Document::new(2, "learn to program in rust today"));
+        index.index(Document::new(3, "learn to program in python today"));
+        index.index(Document::new(4, "learn rust, not python"));
+        let query = Boolean {
+            must: &[Match("learn"), Match("rust")],
+            should: &[Match("today")],
+            must_not: &[Match("python")],
+        };
+        assert_eq!(index.query_ids(&query), vec![2, 1]);
+        assert_eq!(index.query_ids(&And(&[Match("learn"), Match("rust")])), vec![4, 1, 2]);
+        let search_results = index.query(&query);
+        assert_eq!(search_results[0].positions.len(), 3);
+
+        let query = Boolean {
+            must: &[],
+            should: &[Match("today"), Match("python")],
+            must_not: &[Match("rust")],
+        };
+        assert_eq!(index.query_ids(&query), vec![3]);
+    }
+
+    #[test]
+    fn near() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust is a language that is fast"));
+        index.index(Document::new(2, "rust is fast"));
+        index.index(Document::new(3, "rust fast"));
+        index.index(Document::new(4, "fast rust"));
+        index.index(Document::new(5, "rustic and fast"));
+        assert_eq!(index.count(&Near("rust fast", 0)), 1);
+        assert_eq!(index.count(&Near("rust fast", 1)), 2);
+        let search_results = index.query(&Near("rust fast", 5));
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+        // A match spread out by one word scores half as much
+        assert_eq!(search_results[1].positions,
+                   vec![Position::new((0, 4), 0), Position::new((8, 12), 2)]);
+        let unweighted = SearchResult::new(search_results[1].doc,
+                                           search_results[1].positions.clone());
+        assert_eq!(search_results[1].score, unweighted.score / 2.);
+        assert_eq!(index.query(&Near("fast rust", 5)).len(), 1);
+
+        // Documents of the same length, which would tie without the decay
+        index.index(Document::new(6, "rust is very fast"));
+        index.index(Document::new(7, "very so rust fast"));
+        let search_results = index.query(&Near("rust fast", 2));
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![3, 7, 2, 6]);
+    }
+
+    #[test]
+    fn query_blended() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "title: rust | body: a language"));
+        index.index(Document::new(2, "body: rust"));
+        index.index(Document::new(3, "title: python | body: a snake"));
+        let title = Phrase("title rust");
+        let body = Phrase("body rust");
+        let search_results = index.query_blended(&[(title, 3.), (body, 1.)]);
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(search_results[0].score, 3. * index.query(&title)[0].score);
+        assert_eq!(index.query_ids(&Or(&[title, body])), vec![2, 1]);
+
+        let search_results = index.query_blended(&[(Match("rust"), 1.), (Match("snake"), 0.5)]);
+        let ids: Vec<_> = search_results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, vec![2, 1, 3]);
+        assert!(index.query_blended(&[]).is_empty());
+    }
+
+    #[test]
+    fn insert() {
+        let mut index = InvertedIndex::new();
+        assert_eq!(index.insert(Document::new(1, "rust")), Inserted::New);
+        assert_eq!(index.insert(Document::new(2, "python")), Inserted::New);
+        assert_eq!(index.insert(Document::new(1, "rust today")),
+                   Inserted::Replaced(Document::new(1, "rust")));
+        assert_eq!(index.insert(Document::new(1, "rust tomorrow")),
+                   Inserted::Replaced(Document::new(1, "rust today")));
+        assert_eq!(index.search_ids("today"), vec![]);
+        assert_eq!(index.search_ids("tomorrow"), vec![1]);
+    }
+
+    #[test]
+    fn batch() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn rust"));
+        index.index(Document::new(2, "learn python"));
+        index.begin_batch();
+        assert_eq!(index.insert(Document::new(3, "rust today")), Inserted::New);
+        index.index(Document::new(4, "rust tomorrow"));
+        assert_eq!(index.remove(2), Some(Document::new(2, "learn python")));
+        index.index(Document::new(4, "rust and more rust"));
+        assert_eq!(index.search_ids("rust"), vec![1]);
+        assert_eq!(index.search_ids("python"), vec![2]);
+
+        let mut sequential = index.clone();
+        sequential.rollback();
+        sequential.index(Document::new(3, "rust today"));
+        sequential.remove(2);
+        sequential.index(Document::new(4, "rust and more rust"));
+
+        index.commit();
+        assert_eq!(index, sequential);
+        assert_eq!(index.search_ids("rust"), vec![4, 1, 3]);
+        assert_eq!(index.search_ids("python"), vec![]);
+        assert_eq!(index.search_ids("tomorrow"), vec![]);
+
+        index.begin_batch();
+        index.remove(1);
+        index.rollback();
+        index.commit();
+        assert_eq!(index.search_ids("learn"), vec![1]);
+    }
+
+    #[test]
+    fn query_with_analyzer() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "Rust is fun"));
+        index.index(Document::new(2, "rusty nails"));
+        let ids = |results: Vec<SearchResult>| {
+            results.into_iter().map(|result| result.doc.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(index.query(&Match("rusty"))), [2]);
+        // Analyzing the query into ngrams matches the documents containing any of its prefixes.
+        let ngrams = |text: &str| lowercase_ngrams(text);
+        assert_eq!(ids(index.query_with_analyzer(&Match("rusty"), &ngrams)), [2, 1]);
+        // Indexed terms are lowercased, so terms that aren't never match.
+        let verbatim = |text: &str| EnglishUtf8::from_bytes(text);
+        assert_eq!(ids(index.query_with_analyzer(&Match("Rust"), &verbatim)), []);
+        assert_eq!(ids(index.query_with_analyzer(&Match("rust"), &verbatim)), [1, 2]);
+    }
+
+    #[test]
+    fn estimated_memory() {
+        let mut index = InvertedIndex::new();
+        assert_eq!(index.estimated_memory(), 0);
+        index.index(Document::new(1, "learn to program in rust today"));
+        let one_doc = index.estimated_memory();
+        assert!(one_doc > "learn to program in rust today".len());
+        index.index(Document::new(2, "rust is a systems programming language"));
+        let two_docs = index.estimated_memory();
+        assert!(two_docs > one_doc);
+        index.remove(2);
+        assert_eq!(index.estimated_memory(), one_doc);
+    }
+
+    #[test]
+    fn index_pairs() {
+        let mut index = InvertedIndex::new();
+        index.index_pairs(vec![(1, "learn to program in rust today"), (2, "rust is fun")]);
+        assert_eq!(index.search_ids("rust"), [2, 1]);
+        assert_eq!(index.search_ids("program"), [1]);
+        index.index_pairs(Some((2, "haskell is fun")));
+        assert_eq!(index.search_ids("rust"), [1]);
+    }
+
+    #[test]
+    fn query_min_tf() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust rust rust"));
+        index.index(Document::new(2, "rust and rustaceans love rust"));
+        index.index(Document::new(3, "rustaceans rustaceans rustaceans"));
+        index.index(Document::new(4, "rust"));
+        let ids = |results: Vec<SearchResult>| {
+            results.into_iter().map(|result| result.doc.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(index.query_min_tf("rust", 3)), [1]);
+        assert_eq!(ids(index.query_min_tf("rust", 2)), [1, 2]);
+        assert_eq!(ids(index.query_min_tf("rust", 1)), [1, 4, 2]);
+        assert_eq!(ids(index.query_min_tf("rust", 0)).len(), 4);
+        assert_eq!(ids(index.query_min_tf("rust", 4)), []);
+    }
+
+    #[test]
+    fn did_you_mean() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "beat the drum"));
+        index.index(Document::new(2, "a bean and a bear"));
+        index.index(Document::new(3, "bears beat bees"));
+        assert_eq!(index.did_you_mean("beet"), Some("beat".into()));
+        assert_eq!(index.did_you_mean("Beet drun"), Some("beat drum".into()));
+        assert_eq!(index.did_you_mean("beet xylophone"), Some("beat xylophone".into()));
+        assert_eq!(index.did_you_mean("beat"), None);
+        assert_eq!(index.did_you_mean("xylophone"), None);
+        assert_eq!(index.did_you_mean(""), None);
+    }
+
+    #[test]
+    fn raw_scoring() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust is fun"));
+        index.index(Document::new(2,
+                                  "learn rust by writing rust programs, and then more programs \
+                                   than you could ever want"));
+        assert_eq!(index.search_ids("rust"), [1, 2]);
+        index.set_scoring_mode(ScoringMode::Raw);
+        assert_eq!(index.search_ids("rust"), [2, 1]);
+        let results = index.search("rust");
+        assert_eq!(results.iter().map(|result| result.score).collect::<Vec<_>>(), [8., 4.]);
+    }
+
+    #[test]
+    fn query_time_range() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust 1.0 is released").with_timestamp(100));
+        index.index(Document::new(2, "rust 1.1 is released").with_timestamp(200));
+        index.index(Document::new(3, "rust 1.2 is released").with_timestamp(300));
+        index.index(Document::new(4, "rust is released"));
+        index.index(Document::new(5, "go 1.5 is released").with_timestamp(200));
+        let ids = |results: Vec<SearchResult>| {
+            let mut ids: Vec<_> = results.into_iter().map(|result| result.doc.id).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(index.query_time_range(&Match("rust"), 150, 300)), [2, 3]);
+        assert_eq!(ids(index.query_time_range(&Match("rust"), 0, 100)), [1]);
+        assert_eq!(ids(index.query_time_range(&Match("rust"), 301, 1000)), []);
+        assert_eq!(ids(index.query_time_range(&Match("released"), 200, 200)), [2, 5]);
+    }
+
+    #[test]
+    fn tokenize_matches_indexing() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "Learn to"));
+        let mut indexed: Vec<_> = index.index
+                                       .iter()
+                                       .flat_map(|(term, postings)| {
+                                           postings[&1]
+                                               .iter()
+                                               .map(move |&position| (term.clone(), position))
+                                       })
+                                       .collect();
+        indexed.sort_by_key(|&(_, position)| position);
+        assert_eq!(tokenize("Learn to"), indexed);
+        assert_eq!(tokenize("Learn to")[..2],
+                   [("l".into(), Position::new((0, 1), 0)),
+                    ("le".into(), Position::new((0, 2), 0))]);
     }
 
-    /// A helper method for performing a Match query
-    pub fn search(&self, query: &str) -> Vec<SearchResult> {
-        self.query(&Match(query))
+    #[test]
+    fn search_excluding() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "rust and go"));
+        index.index(Document::new(2, "rust and java"));
+        index.index(Document::new(3, "go and java"));
+        let results = index.search_excluding("rust", "go");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].doc.id, 2);
+        assert_eq!(results[0].positions, [Position::new((0, 4), 0)]);
+        let ids: Vec<_> = index.search_excluding("java", "rust")
+                               .into_iter()
+                               .map(|result| result.doc.id)
+                               .collect();
+        assert_eq!(ids, [3]);
+        assert!(index.search_excluding("go", "go").is_empty());
     }
 
-    fn postings(&self, query: &str) -> PostingsMap {
-        LowercaseFilter::from_bytes(query)
-            .into_iter()
-            .map(Result::unwrap)
-            .unique()
-            .flat_map(|token| self.index.get(&token.token))
-            .flat_map(|map| map)
-            .collect::<MergePostingsMap>()
-            .0
+    #[test]
+    fn precompute_prefixes() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "programming is fun"));
+        index.index(Document::new(3, "pro tips"));
+        let queries = [Prefix("p"), Prefix("pro"), Prefix("r"), PhrasePrefix("to pro")];
+        let summarize = |results: Vec<SearchResult>| {
+            results.into_iter()
+                   .map(|result| (result.doc.id, result.positions, result.score))
+                   .collect::<Vec<_>>()
+        };
+        let uncached: Vec<_> = queries.iter().map(|q| summarize(index.query(q))).collect();
+        index.precompute_prefixes(&["p", "pro"]);
+        assert_eq!(index.prefix_cache.len(), 2);
+        let cached: Vec<_> = queries.iter().map(|q| summarize(index.query(q))).collect();
+        assert_eq!(cached, uncached);
+        index.index(Document::new(4, "pro"));
+        assert!(index.prefix_cache.is_empty());
+        assert_eq!(index.query(&Prefix("pro")).len(), 4);
+        index.precompute_prefixes(&["pro"]);
+        index.remove(4);
+        assert!(index.prefix_cache.is_empty());
+        assert_eq!(index.query(&Prefix("pro")).len(), 3);
+    }
 
+    #[test]
+    fn result_metadata() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today")
+                        .with_metadata("Learning Rust"));
+        index.index(Document::new(2, "rust is fun"));
+        let results = index.search("rust");
+        assert_eq!(results[0].metadata(), None);
+        assert_eq!(results[1].metadata(), Some("Learning Rust"));
+        assert!(index.search("learning").is_empty());
     }
 
-    fn phrase(&self, phrase: &str) -> PostingsMap {
-        let terms: Vec<_> = LowercaseFilter::from_bytes(phrase)
-                                .into_iter()
-                                .map(Result::unwrap)
-                                .map(|token| token.token)
-                                .collect();
-        let postings: Vec<_> = terms.windows(2)
-                                    .map(|adjacent_terms| {
-                                        let term0 = &adjacent_terms[0];
-                                        let term1 = &adjacent_terms[1];
-                                        if let (Some(posting0), Some(posting1)) =
-                                               (self.index.get(term0), self.index.get(term1)) {
-                                            posting0.intersect_positionally(posting1)
-                                        } else {
-                                            PostingsMap::new()
-                                        }
-                                    })
-                                    .collect();
-        postings.intersect_postings()
+    #[test]
+    fn query_truncated() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "café programs"));
+        let results = index.query_truncated(&Match("program rust"), 12);
+        assert_eq!(results.len(), 2);
+        let result = results.iter().find(|result| result.doc.id == 1).unwrap();
+        assert_eq!(result.doc.content, "learn to pro");
+        assert!(result.truncated);
+        assert_eq!(result.positions, [Position::new((9, 12), 2)]);
+        assert_eq!(result.highlight("<b>", "</b>"), "learn to <b>pro</b>");
+        assert_eq!(result.score, index.search("program rust")[0].score);
+        let results = index.query_truncated(&Match("café"), 4);
+        assert_eq!(results[0].doc.content, "caf");
+        assert_eq!(results[0].highlight("<b>", "</b>"), "<b>caf</b>");
+        let results = index.query_truncated(&Match("café"), 100);
+        assert_eq!(results[0].doc.content, "café programs");
+        assert!(!results[0].truncated);
+        index.index(Document::multi_valued(3, &["red apple", "blue sky", "green grass"]));
+        let results = index.query_truncated(&Match("red"), 4);
+        assert_eq!(results[0].doc.values(), ["red "]);
+        let results = index.query_truncated(&Match("red"), 13);
+        assert_eq!(results[0].doc.values(), ["red apple", "blu"]);
+        let results = index.query_truncated(&Match("red"), 19);
+        assert_eq!(results[0].doc.values(), ["red apple", "blue sky", ""]);
     }
 
-    fn prefix(&self, prefix: &str) -> PostingsMap {
-        if prefix.is_empty() {
-            return PostingsMap::new();
-        }
+    #[test]
+    fn idf_weighting() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "java today"));
+        index.index(Document::new(2, "java tools"));
+        index.index(Document::new(3, "java class"));
+        index.index(Document::new(4, "rust today"));
+        assert_eq!(index.search_ids("rust java"), [1, 2, 3, 4]);
+        index.set_idf_weighting(true);
+        assert_eq!(index.search_ids("rust java"), [4, 1, 2, 3]);
+        let results = index.search("rust java");
+        assert_eq!(results[0].score, 4. * index.idf("rust") / 10f32.sqrt());
+        assert_eq!(results[1].score, 4. * index.idf("java") / 10f32.sqrt());
 
-        let min = Included(prefix);
-        let mut max: String = prefix.into();
-        let max = if let Some(next_char) = max.pop().unwrap().successor() {
-            max.push(next_char);
-            Excluded(&max)
-        } else {
-            Unbounded
-        };
-        self.index
-            .range(min, max)
-            .map(|(_k, v)| v)
-            .flat_map(|map| map)
-            .collect::<MergePostingsMap>()
-            .0
+        // Words are weighted by the IDF of the terms they're indexed under
+        index.index(Document::new(5, "don't panic"));
+        index.index(Document::new(6, "dont"));
+        index.index(Document::new(7, "well-known"));
+        let results = index.search("dont");
+        let result = results.iter().find(|result| result.doc.id == 5).unwrap();
+        assert_eq!(result.score, 5. * index.idf("dont") / 11f32.sqrt());
+        let results = index.query(&Spanned(&Match("well")));
+        assert_eq!(results[0].score, 10. * index.idf("wellknown") / 10f32.sqrt());
+    }
 
+    #[test]
+    fn prune_terms() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn rust fun"));
+        index.index(Document::new(2, "rust is fun"));
+        index.index(Document::new(3, "rusty fun"));
+        index.index(Document::new(4, "fun"));
+        index.prune_terms(2, 0.9);
+        // Only in document 1
+        assert!(index.search("learn").is_empty());
+        assert!(index.query(&Term("le")).is_empty());
+        // In all of the documents
+        assert!(index.search("fun").is_empty());
+        assert!(index.query(&Term("f")).is_empty());
+        assert_eq!(index.query(&Term("r")).len(), 3);
+        assert_eq!(index.search_ids("rust"), [3, 2, 1]);
+        assert_eq!(index.doc_frequency("rusty"), 0);
+        let pruned = index.clone();
+        index.prune_terms(0, 1.);
+        assert_eq!(index, pruned);
     }
 
-    fn query_rec(&self, query: &Query) -> PostingsMap {
-        match *query {
-            Match(query) => self.postings(query),
-            And(queries) => {
-                let postings: Vec<_> = queries.iter().map(|q| self.query_rec(q)).collect();
-                postings.intersect_postings()
-            }
-            Or(queries) => queries.into_iter()
-                                  .map(|q| self.query_rec(q))
-                                  .flat_map(|map| map)
-                                  .collect::<MergePostingsMap>()
-                                  .0,
-            Phrase(phrase) => self.phrase(phrase),
-            Prefix(prefix) => self.prefix(prefix),
-        }
+    #[test]
+    fn multi_valued() {
+        let mut index = InvertedIndex::new();
+        let tags = Document::multi_valued(1, vec!["red", "blue", "dark green"]);
+        assert_eq!(tags.content, "red\nblue\ndark green");
+        assert_eq!(tags.values(), ["red", "blue", "dark green"]);
+        index.index(tags);
+        index.index(Document::new(2, "red blue"));
+        assert_eq!(index.search_ids("red"), [2, 1]);
+        assert_eq!(index.search_ids("blue"), [2, 1]);
+        assert_eq!(index.query_ids(&Phrase("red blue")), [2]);
+        assert!(index.query(&Near("blue dark", 10)).is_empty());
+        assert_eq!(index.query_ids(&Phrase("dark green")), [1]);
+        let results = index.query(&Phrase("dark green"));
+        assert_eq!(results[0].highlight("<", ">"), "red\nblue\n<dark> <green>");
+        assert_eq!(Document::new(3, "red blue").values(), ["red blue"]);
     }
 
-    fn compute_results(&self, postings: PostingsMap) -> Vec<SearchResult> {
-        let mut results: Vec<_> = postings.into_iter()
-                                          .map(|(doc_id, positions)| {
-                                              SearchResult::new(&self.docs[&doc_id], positions)
-                                          })
-                                          .collect();
-        results.sort_by(|result1, result2| result2.score.partial_cmp(&result1.score).unwrap());
-        results
+    #[test]
+    fn search_highlighted() {
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "rust is fun"));
+        index.index(Document::new(3, "haskell is fun"));
+        let expected: Vec<_> = index.search("rust prog")
+                                    .into_iter()
+                                    .map(|result| (result.doc.id, result.highlight("<b>", "</b>")))
+                                    .collect();
+        assert_eq!(index.search_highlighted("rust prog", "<b>", "</b>"), expected);
+        assert_eq!(expected,
+                   [(1, "learn to <b>prog</b>ram in <b>rust</b> today".into()),
+                    (2, "<b>rust</b> is fun".into())]);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use Query::*;
-    use Document;
-    use InvertedIndex;
-    use Position;
-    use SearchResult;
-    use std::collections::BTreeMap;
+    #[test]
+    fn ascii_locale() {
+        let mut index = InvertedIndex::new();
+        index.set_locale(Locale::Ascii);
+        index.index(Document::new(1, "Äpfel and APPLES"));
+        assert_eq!(index.search_ids("apples"), [1]);
+        assert_eq!(index.search_ids("ÄPFEL"), [1]);
+        assert_eq!(index.query_ids(&Phrase("äpfel and")), []);
+        assert!(!index.any_doc_matches(&Match("äpfel")));
+        assert!(index.any_doc_matches(&Match("Äpfel")));
+        let mut index = InvertedIndex::new();
+        index.index(Document::new(1, "Äpfel and APPLES"));
+        assert_eq!(index.search_ids("äpfel"), [1]);
+    }
 
     #[test]
-    fn ngrams() {
+    fn corpus_stats() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "learn to program in rust today");
-        let doc2 = Document::new(2, "what did you today do");
-        index.index(doc1.clone());
-        index.index(doc2.clone());
-        let search_results = index.search("to");
-        let expected: BTreeMap<_, _> =
-            [(doc1.id.clone(),
-              vec![Position::new((6, 8), 1), Position::new((25, 27), 5)]),
-             (doc2.id.clone(), vec![Position::new((13, 15), 3)])]
-                .iter()
-                .cloned()
-                .collect();
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id])
+        assert_eq!(index.corpus_stats().avg_content_len(), 0.);
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.index(Document::new(2, "rust is fun"));
+        index.index(Document::new(3, "haskell is fun"));
+        index.index(Document::new(2, "rust is fast"));
+        index.update_all(vec![Document::new(4, "go is fun"), Document::new(1, "learn rust")]);
+        index.remove(3);
+        index.remove(5);
+        let stats = index.corpus_stats();
+        assert_eq!(stats,
+                   CorpusStats {
+                       num_docs: index.docs.len(),
+                       total_content_len: index.docs
+                                               .values()
+                                               .map(|doc| doc.content.len())
+                                               .sum::<usize>(),
+                   });
+        assert_eq!(stats.num_docs, 3);
+        assert_eq!(stats.avg_content_len(), (10 + 12 + 9) as f32 / 3.);
+        for (term, postings) in &index.index {
+            let df = index.docs
+                          .values()
+                          .filter(|doc| tokenize(&doc.content).iter().any(|&(ref t, _)| t == term))
+                          .count();
+            assert_eq!(postings.len(), df);
+            assert_eq!(index.doc_frequency(term), df);
         }
-        assert_eq!("learn <span class=highlight>to</span> program in rust <span \
-                    class=highlight>to</span>day",
-                   search_results.iter()
-                                 .find(|search_result| search_result.doc.id == doc1.id)
-                                 .unwrap()
-                                 .highlight("<span class=highlight>", "</span>"));
-
     }
 
     #[test]
-    fn highlight() {
+    fn spanned() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(2,
-                                 "Won\u{2019}t this split the ecosystem? Will everyone use?");
-        index.index(doc1.clone());
-        let expected = "Won\u{2019}t this split the *e*cosystem? Will *e*veryone use?";
-        let search_results = index.search("e");
-        assert_eq!(1, search_results.len());
-        assert_eq!(search_results[0].highlight("*", "*"), expected);
+        index.index(Document::new(1, "learn to program in rust, programs don't lie"));
+        index.index(Document::new(2, "rusty progress"));
+        let results = index.query(&Spanned(&Prefix("pro")));
+        assert_eq!(results.len(), 2);
+        let doc1 = results.iter().find(|result| result.doc.id == 1).unwrap();
+        assert_eq!(doc1.positions,
+                   [Position::new((9, 16), 2), Position::new((26, 34), 5)]);
+        let results = index.query(&Spanned(&Or(&[Match("rust do"), Prefix("ru")])));
+        let doc1 = results.iter().find(|result| result.doc.id == 1).unwrap();
+        assert_eq!(doc1.positions,
+                   [Position::new((20, 24), 4), Position::new((35, 40), 6)]);
+        let doc2 = results.iter().find(|result| result.doc.id == 2).unwrap();
+        assert_eq!(doc2.positions, [Position::new((0, 5), 0)]);
+        assert_eq!(doc2.highlight("<", ">"), "<rusty> progress");
     }
 
     #[test]
-    fn unicode() {
+    fn spanned_literal() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "嗨, 您好");
-        index.index(doc.clone());
-        let to_search = "您";
-        let search_results = index.search(to_search);
-        let &SearchResult { ref doc, ref positions, .. } = search_results.iter().next().unwrap();
-        let Position{offsets:(begin, end), ..} = positions[0];
-        assert_eq!(&doc.content()[begin..end], to_search);
+        index.index(Document::new(1, "learn to program in rust, programs don't lie"));
+        let results = index.query(&Spanned(&Literal("gram")));
+        assert_eq!(results[0].positions,
+                   [Position::new((9, 16), 2), Position::new((26, 34), 5)]);
+        let results = index.query(&Spanned(&Literal("ust, pro")));
+        assert_eq!(results[0].positions, [Position::new((20, 34), 4)]);
+        let results = index.query(&Spanned(&Literal(" rust")));
+        assert_eq!(results[0].positions, [Position::new((19, 24), 4)]);
     }
 
     #[test]
-    fn update_doc() {
+    fn missing_docs_skipped() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "abc åäö");
-        index.index(doc);
-        let doc = Document::new(0, "different");
-        index.index(doc);
-        let search_results = index.search("å");
-        assert!(search_results.is_empty());
-        assert_eq!(index.docs.len(), 1);
+        index.index(Document::new(1, "learn rust"));
+        index.index(Document::new(2, "rust today"));
+        assert_eq!(index.validate(), Ok(()));
+        // Corrupt the index, leaving doc 2's postings behind
+        index.docs.remove(&2);
+        assert_eq!(index.validate(),
+                   Err(IndexError::MissingDocument {
+                       term: "r".into(),
+                       doc_id: 2,
+                   }));
+        assert_eq!(index.search_ids("rust"), vec![1]);
+        assert_eq!(index.query_ids(&Match("rust")), vec![1]);
+        assert_eq!(index.query(&Phrase("rust today")).len(), 0);
+        assert_eq!(index.query(&Spanned(&Prefix("tod"))).len(), 0);
+        assert_eq!(index.query(&Near("rust today", 1)).len(), 0);
+        assert_eq!(index.count(&Match("rust")), 1);
+        assert_eq!(index.count(&Match("today")), 0);
+        assert!(!index.any_doc_matches(&Match("today")));
+        assert!(!index.any_doc_matches(&Term("tod")));
+        assert!(!index.any_doc_matches(&And(&[Match("rust"), Match("today")])));
+        assert!(!index.any_doc_matches(&Phrase("rust today")));
+        assert!(index.any_doc_matches(&Match("rust")));
     }
 
     #[test]
-    fn ranking() {
+    fn or_overlapping_prefixes() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "beat");
-        index.index(doc.clone());
-        let doc2 = Document::new(1, "beast");
-        index.index(doc2);
-        let search_results = index.search("be");
-        assert_eq!(index.docs.len(), 2);
-        // "beat" should be first, since it's a closer match
-        assert_eq!(search_results[0].doc.id, doc.id);
+        index.index(Document::new(1, "bear"));
+        let results = index.query(&Or(&[Prefix("be"), Prefix("bea")]));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].positions, [Position::new((0, 4), 0)]);
+        assert_eq!(results[0].highlight("<", ">"), "<bear>");
+
+        // The terms' own spans both start at 0; only the longer one is kept
+        let results = index.query(&Or(&[Match("be"), Match("bea")]));
+        assert_eq!(results[0].positions, [Position::new((0, 3), 0)]);
     }
 
     #[test]
-    fn duplicate_term() {
-        let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "beat");
-        index.index(doc.clone());
-        let search_results = index.search("be be");
-        assert_eq!(search_results.len(), 1);
+    fn combined_vocabulary() {
+        let mut index1 = InvertedIndex::new();
+        index1.index(Document::new(1, "rust today"));
+        let mut index2 = InvertedIndex::new();
+        index2.index(Document::new(2, "rust tomorrow"));
+        index2.index(Document::new(3, "learn rust"));
+        let vocabulary = super::combined_vocabulary(&[&index1, &index2]);
+        assert_eq!(vocabulary["rust"], 3);
+        assert_eq!(vocabulary["to"], 2);
+        assert_eq!(vocabulary["today"], 1);
+        assert_eq!(vocabulary["learn"], 1);
+        assert!(super::combined_vocabulary(&[]).is_empty());
     }
 
     #[test]
-    fn duplicate_term2() {
+    fn stop_words() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "beat");
-        index.index(doc.clone());
-        let search_results = index.search("be b");
-        assert_eq!(search_results.len(), 1);
-        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+        index.set_stop_words(["the".into()].iter().cloned().collect());
+        index.index(Document::new(1, "The quick brown fox"));
+        index.index(Document::new(2, "quick the fox"));
+        assert!(index.search("the").is_empty());
+        assert_eq!(index.doc_frequency("the"), 0);
+
+        let results = index.query(&Phrase("quick brown"));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].doc.id, 1);
+        assert_eq!(results[0].positions,
+                   [Position::new((4, 9), 1), Position::new((10, 15), 2)]);
+        assert_eq!(index.query_ids(&Phrase("the quick brown")), [1]);
+        assert_eq!(index.query_ids(&Phrase("quick fox")), Vec::<usize>::new());
+        // The stop word's position can be taken by any word
+        assert_eq!(index.query_ids(&Phrase("quick the fox")), [2, 1]);
+        assert_eq!(index.query_ids(&PhrasePrefix("quick the f")), [2, 1]);
     }
 
     #[test]
-    fn lowercase_search() {
+    fn vacuum() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "BeAt");
-        index.index(doc.clone());
-        let search_results = index.search("bE");
-        assert_eq!(search_results.len(), 1);
-        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+        let mut fresh = InvertedIndex::new();
+        for id in 0..50 {
+            let doc = Document::new(id, format!("doc number{} about rust and word{}", id, id % 7));
+            index.index(doc.clone());
+            if id % 5 == 0 {
+                fresh.index(doc);
+            } else {
+                index.index(Document::new(id, format!("replaced{} doc", id)));
+            }
+        }
+        for id in 0..50 {
+            if id % 5 != 0 {
+                index.remove(id);
+            }
+        }
+        let queries = [Match("rust"), Prefix("word"), Phrase("about rust"), Match("number1")];
+        let before: Vec<_> = queries.iter()
+                                    .map(|query| index.query_truncated(query, usize::MAX))
+                                    .collect();
+        index.vacuum();
+        for (query, before) in queries.iter().zip(before) {
+            let after = index.query_truncated(query, usize::MAX);
+            assert_eq!(format!("{:?}", after), format!("{:?}", before));
+        }
+        assert_eq!(index.index, fresh.index);
+        assert!(index.index.values().all(|postings| !postings.is_empty()));
     }
 
     #[test]
-    fn lowercase_index() {
+    fn numeric_range() {
         let mut index = InvertedIndex::new();
-        let doc = Document::new(0, "BeAt");
-        index.index(doc.clone());
-        let search_results = index.search("be");
-        assert_eq!(search_results.len(), 1);
-        assert_eq!(search_results[0].positions, vec![Position::new((0, 2), 0)]);
+        index.set_index_numbers(true);
+        index.index(Document::new(1, "rust released in 2015"));
+        index.index(Document::new(2, "a book for $45"));
+        index.index(Document::new(3, "a mug for $8, or 2 for $12"));
+        index.index(Document::new(4, "no numbers here"));
+        assert_eq!(index.query_ids(&NumericRange(10, 50)), [2, 3]);
+        assert_eq!(index.query_ids(&NumericRange(2000, 2020)), [1]);
+        assert_eq!(index.query_ids(&NumericRange(46, 1999)), Vec::<usize>::new());
+        assert_eq!(index.query_ids(&NumericRange(50, 10)), Vec::<usize>::new());
+        assert_eq!(index.query_ids(&And(&[NumericRange(0, 100), Match("book")])), [2]);
+
+        index.remove(2);
+        index.index(Document::new(3, "a mug for $9"));
+        assert_eq!(index.query_ids(&NumericRange(10, 50)), Vec::<usize>::new());
+        assert_eq!(index.numbers.keys().cloned().collect::<Vec<_>>(), [9, 2015]);
     }
 
     #[test]
-    fn and() {
+    fn numeric_range_replaced() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "learn to program in rust today");
-        let doc2 = Document::new(2, "what did you today do");
-        let doc3 = Document::new(3, "what did you do yesterday");
-        index.index(doc1.clone());
-        index.index(doc2.clone());
-        index.index(doc3.clone());
-        let search_results = index.query(&And(&[Match("today"), Match("you")]));
-        let expected: BTreeMap<_, _> = [(doc2,
-                                         vec![Position::new((9, 12), 2),
-                                              Position::new((13, 18), 3)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[search_result.doc])
-        }
+        index.set_index_numbers(true);
+        index.index(Document::new(1, "costs 45"));
+        index.update_all(vec![Document::new(1, "costs 9")]);
+        assert_eq!(index.query_ids(&NumericRange(40, 50)), Vec::<usize>::new());
+        assert_eq!(index.query_ids(&NumericRange(0, 10)), [1]);
+
+        index.begin_batch();
+        index.index(Document::new(1, "costs 12"));
+        index.commit();
+        assert_eq!(index.query_ids(&NumericRange(0, 10)), Vec::<usize>::new());
+        assert_eq!(index.numbers.keys().cloned().collect::<Vec<_>>(), [12]);
     }
 
     #[test]
-    fn and_or() {
+    fn numbers() {
+        let numbers = |content| super::numbers(content).into_iter().collect::<Vec<_>>();
+        assert_eq!(numbers("costs $1,000 (or -5), in 2015."), [-5, 1000, 2015]);
+        assert_eq!(numbers("1.5 or 2.99 or 10-20 or 12,34 or 1234,567"), Vec::<i64>::new());
+        assert_eq!(numbers("version v2 of the 3rd edition, 45%"), [45]);
+    }
+
+    #[test]
+    fn length_norms() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "learn to program in rust today");
-        let doc2 = Document::new(2, "what did you today do");
-        let doc3 = Document::new(3, "what did you do yesterday");
-        index.index(doc1.clone());
-        index.index(doc2.clone());
-        index.index(doc3.clone());
-        let search_results = index.query(&Or(&[Match("you"),
-                                               And(&[Match("today"), Match("you")])]));
-        let expected: BTreeMap<_, _> = [(doc2.id,
-                                         vec![Position::new((9, 12), 2),
-                                              Position::new((13, 18), 3)]),
-                                        (doc3.id, vec![Position::new((9, 12), 2)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id])
+        index.index(Document::new(1, "learn to program in rust today"));
+        index.update_all(vec![Document::new(2, "rust"), Document::new(3, "what about rust")]);
+        for result in index.search("rust") {
+            assert_eq!(result.score, SearchResult::new(result.doc, result.positions.clone()).score);
         }
+        index.index(Document::new(2, "rust is longer now"));
+        let results = index.search("rust");
+        let result = results.iter().find(|result| result.doc.id == 2).unwrap();
+        assert_eq!(result.score, 4. / 18f32.sqrt());
+        index.remove(2);
+        assert_eq!(index.length_norms.keys().cloned().collect::<Vec<_>>(), [1, 3]);
     }
 
     #[test]
-    fn phrase() {
+    fn index_with_mode() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "learn to program in rust today");
-        index.index(doc1.clone());
-        let search_results = index.query(&Phrase("learn to program"));
-        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
-                                         vec![Position::new((0, 5), 0),
-                                              Position::new((6, 8), 1),
-                                              Position::new((9, 16), 2)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
-        }
-        let search_results = index.query(&Phrase("lear t pro"));
-        let expected: BTreeMap<_, _> = [(doc1.id,
-                                         vec![Position::new((0, 4), 0),
-                                              Position::new((6, 7), 1),
-                                              Position::new((9, 12), 2)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
-        }
+        assert_eq!(index.index_with_mode(Document::new(1, "rust"), IndexMode::Error),
+                   Ok(Inserted::New));
+        assert_eq!(index.index_with_mode(Document::new(1, "rust today"), IndexMode::Error),
+                   Err(IndexError::DuplicateId { doc_id: 1 }));
+        assert_eq!(index.search_ids("today"), vec![]);
+
+        assert_eq!(index.index_with_mode(Document::new(1, "rust today"), IndexMode::Skip),
+                   Ok(Inserted::Skipped));
+        assert_eq!(index.search_ids("today"), vec![]);
+        assert_eq!(index.index_with_mode(Document::new(2, "rust today"), IndexMode::Skip),
+                   Ok(Inserted::New));
+
+        assert_eq!(index.index_with_mode(Document::new(1, "rust tomorrow"), IndexMode::Replace),
+                   Ok(Inserted::Replaced(Document::new(1, "rust"))));
+        assert_eq!(index.search_ids("tomorrow"), vec![1]);
     }
 
     #[test]
-    fn phrase2() {
+    fn length_norm() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "is is is");
-        index.index(doc1.clone());
-        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
-                                         vec![Position::new((0, 1), 0),
-                                              Position::new((3, 4), 1),
-                                              Position::new((6, 7), 2)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        let search_results = index.query(&Phrase("i i"));
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
-        }
+        index.index(Document::new(1, "rust rust and lots of other words here"));
+        index.index(Document::new(2, "rust"));
+        assert_eq!(index.search_ids("rust"), [2, 1]);
+        index.set_scoring_mode(ScoringMode::LengthNormalized(LengthNorm::Linear));
+        assert_eq!(index.search_ids("rust"), [2, 1]);
+        index.set_scoring_mode(ScoringMode::LengthNormalized(LengthNorm::Log));
+        index.index(Document::new(3, "rust today"));
+        assert_eq!(index.search("rust")[2].score, 4. / 11f32.ln());
+        index.set_scoring_mode(ScoringMode::Raw);
+        assert_eq!(index.search("rust")[0].score, 8.);
+        index.set_scoring_mode(ScoringMode::LengthNormalized(LengthNorm::Sqrt));
+        assert_eq!(index.search("rust")[0].score, 2.);
     }
 
     #[test]
-    fn prefix() {
+    fn length_norm_raw_vs_sqrt() {
         let mut index = InvertedIndex::new();
-        let doc1 = Document::new(1, "is is is");
-        index.index(doc1.clone());
-        let expected: BTreeMap<_, _> = [(doc1.id.clone(),
-                                         vec![Position::new((0, 2), 0),
-                                              Position::new((3, 5), 1),
-                                              Position::new((6, 8), 2)])]
-                                           .iter()
-                                           .cloned()
-                                           .collect();
-        let search_results = index.query(&Prefix("i"));
-        assert_eq!(search_results.len(), expected.len());
-        for search_result in &search_results {
-            assert_eq!(&search_result.positions, &expected[&search_result.doc.id]);
-        }
+        index.index(Document::new(1, "rust and rust again, a long document about rust"));
+        index.index(Document::new(2, "rust"));
+        // Normalized, the short document matches a larger share of its content
+        assert_eq!(index.search_ids("rust"), [2, 1]);
+        // Unnormalized, the long document matches more content
+        index.set_scoring_mode(ScoringMode::Raw);
+        assert_eq!(index.search_ids("rust"), [1, 2]);
     }
 
     #[test]
-    fn char_len_change() {
+    fn contains_term() {
         let mut index = InvertedIndex::new();
-        let s: String = "İİ".into();
-        let doc1 = Document::new(1, s.clone());
-        index.index(doc1.clone());
-        assert_eq!(index.index["i̇i̇"][&1][0].offsets.1, 4);
+        index.index(Document::new(1, "Learn Rust"));
+        assert!(index.contains_term("rust"));
+        assert!(index.contains_term("lea"));
+        assert!(!index.contains_term("Rust"));
+        assert!(!index.contains_term("python"));
+        index.remove(1);
+        assert!(!index.contains_term("rust"));
     }
 }
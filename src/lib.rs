@@ -1,5 +1,4 @@
-#![feature(plugin, unboxed_closures, core, iter_arith, custom_attribute, slice_patterns,
-           collections_bound, btree_range, associated_type_defaults)]
+#![feature(plugin, core, iter_arith, custom_attribute, associated_type_defaults)]
 #![plugin(clippy)]
 #![deny(missing_docs)]
 
@@ -40,8 +39,7 @@
 //! * `Match` - The simplest query. Takes a string argument and returns any documents that match
 //!             the string. `index.search(str)` is shorthand for `index.query(Match(str))`.
 //! * `Phrase` - An exact-match query. Takes a string argument and returns any documents that
-//!              contain the exact string. n.b. the `InvertedIndex` may return false positives in
-//!              some cases.
+//!              contain the string's words in order, ignoring case and punctuation.
 //! * `And` - Composes a number of queries into a single query that restricts the results to the
 //!           documents that are returned for each of the sub-queries.
 //! * `Or` - Composes a number of queries into a single query that returns all the documents that
@@ -51,7 +49,9 @@
 //! The returned search results are ordered based on document relevance to the search query, sorted
 //! descending. Currently, relevance for each document is computed based on the length of matching
 //! content divided by the square root of the document length. This helps to ensure that longer
-//! documents don't receive too unfair of an advantage over shorter documents.
+//! documents don't receive too unfair of an advantage over shorter documents. Results with equal
+//! scores are ordered by when their documents were inserted, so the order of results is fully
+//! deterministic and doesn't change as other documents are indexed or removed.
 //!
 //! ## Highlighting
 //! Search results include the positions in the document that matched the query. There is a helper
@@ -71,6 +71,7 @@
 
 extern crate itertools;
 extern crate rustc_serialize;
+extern crate unicode_segmentation;
 extern crate core;
 
 /// Contains utility methods used in the rest of the crate.
@@ -83,10 +84,14 @@ mod query;
 mod search_result;
 mod tokenizers;
 
-pub use index::InvertedIndex;
+pub use index::{CorpusStats, IndexError, IndexMode, Inserted, InvertedIndex, QueryMetrics,
+                combined_vocabulary};
 pub use document::Document;
-pub use search_result::SearchResult;
-pub use postings::{MergePostingsMap, PostingsMap, PostingsIntersect, PositionalIntersect};
-pub use query::Query;
-pub use tokenizers::{EnglishUtf8, NgramsFilter, LowercaseFilter, Position, Tokenizer, Token,
-                     lowercase_ngrams};
+pub use search_result::{LengthNorm, OwnedSearchResult, ScoringMode, SearchResult, merge_results};
+pub use postings::{MergePostings, MergePostingsMap, PostingsMap, PostingsMapExt,
+                   PostingsIntersect, PostingsMerge, PositionalIntersect, SynonymPosition};
+pub use query::{FuzzyKind, NormalizedQuery, Query, QueryError};
+pub use tokenizers::{Analyzer, DelimiterUtf8, EnglishUtf8, Locale, NgramsFilter, LowercaseFilter,
+                     MaxLengthFilter, Position, StopWordsFilter, SuffixNgramsFilter, Tokenizer,
+                     TokenizerChain, Token, TransformFilter, UnicodeWordsUtf8, WordSplitFilter,
+                     lowercase_ngrams, tokenize};
@@ -1,5 +1,7 @@
-use std::cmp::Ordering;
+use std::cmp;
 use std::collections::BTreeMap;
+use std::collections::btree_map::Keys;
+use std::iter::Peekable;
 
 use tokenizers::Position;
 use util::*;
@@ -14,26 +16,78 @@ impl Merge for Position {
     }
 }
 
+/// A position that merges with any position whose text overlaps it, even at a different token
+/// position, unlike `Position`, which only merges with positions of the same token. This suits
+/// positions produced at the same location by different tokens, e.g. by synonym expansion.
+/// The merged position spans both positions' text and takes the lower token position.
+#[derive(Copy, Clone, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct SynonymPosition(pub Position);
+
+impl Merge for SynonymPosition {
+    fn merge(self, other: SynonymPosition) -> Option<SynonymPosition> {
+        let (SynonymPosition(this), SynonymPosition(other)) = (self, other);
+        let overlaps = this.offsets == other.offsets ||
+                       other.offsets.0 < this.offsets.1 && this.offsets.0 < other.offsets.1;
+        if overlaps {
+            let offsets = (cmp::min(this.offsets.0, other.offsets.0),
+                           cmp::max(this.offsets.1, other.offsets.1));
+            let position = cmp::min(this.position, other.position);
+            Some(SynonymPosition(Position::new(offsets, position)))
+        } else {
+            None
+        }
+    }
+}
+
 /// A postings map (doc id => positions) for a single term.
 /// Records which Documents contain the term, and at which locations in the documents.
-pub type PostingsMap = BTreeMap<usize, Vec<Position>>;
+/// Documents are identified by `usize` ids by default, but any `Ord + Clone` id type works with
+/// the postings traits.
+pub type PostingsMap<Id = usize> = BTreeMap<Id, Vec<Position>>;
 
 /// A MergeCoalesceMap for postings.
-pub type MergePostingsMap = MergeCoalesceMap<usize, Vec<Position>>;
+pub type MergePostingsMap<Id = usize> = MergeCoalesceMap<Id, Vec<Position>>;
+
+/// An extension trait adding constructors and accessors to `PostingsMap`s.
+pub trait PostingsMapExt<Id>: Sized {
+    /// Builds a postings map from `(doc id, position)` pairs given in any order. Each document's
+    /// positions are sorted, and positions of the same token that overlap or touch are coalesced,
+    /// as when indexing.
+    fn from_positions<I>(positions: I) -> Self where I: IntoIterator<Item = (Id, Position)>;
+
+    /// Returns an iterator over the ids of the documents in the postings map, in ascending order.
+    fn doc_ids(&self) -> Keys<Id, Vec<Position>>;
+}
+
+impl<Id: Ord> PostingsMapExt<Id> for PostingsMap<Id> {
+    fn from_positions<I>(positions: I) -> PostingsMap<Id>
+        where I: IntoIterator<Item = (Id, Position)>
+    {
+        let mut postings = PostingsMap::new();
+        for (doc_id, position) in positions {
+            postings.entry(doc_id).or_insert_with(Vec::new).search_coalesce(0, position);
+        }
+        postings
+    }
+
+    fn doc_ids(&self) -> Keys<Id, Vec<Position>> {
+        self.keys()
+    }
+}
 
 /// An extension trait for slices of `PostingsMap`s
 /// that enables computing their intersection.
-pub trait PostingsIntersect {
+pub trait PostingsIntersect<Id> {
     /// Computes the map containing the intersection of the the maps in self
-    fn intersect_postings(self) -> PostingsMap;
+    fn intersect_postings(self) -> PostingsMap<Id>;
 }
 
-impl<'a> PostingsIntersect for &'a [PostingsMap] {
-    fn intersect_postings(self) -> PostingsMap {
-        match self {
-            [] => PostingsMap::new(),
-            [ref posting] => posting.clone(),
-            [ref posting0, rest..] => {
+impl<'a, Id: Ord + Clone> PostingsIntersect<Id> for &'a [PostingsMap<Id>] {
+    fn intersect_postings(self) -> PostingsMap<Id> {
+        match self.split_first() {
+            None => PostingsMap::new(),
+            Some((posting, rest)) if rest.is_empty() => posting.clone(),
+            Some((posting0, rest)) => {
                 self.intersection()
                     .map(|doc_id| {
                         let mut positions = posting0[doc_id].clone();
@@ -48,6 +102,81 @@ impl<'a> PostingsIntersect for &'a [PostingsMap] {
     }
 }
 
+/// An extension trait for slices of `PostingsMap`s, e.g. the postings of several terms, that
+/// enables computing their union.
+pub trait PostingsMerge<Id> {
+    /// Computes the map containing the union of the maps in self, with each document's positions
+    /// merged, as when collecting into a `MergePostingsMap`.
+    fn merge_postings(self) -> PostingsMap<Id>;
+
+    /// Computes the union of the maps in self like `merge_postings`, but keeps which map each of
+    /// a document's positions came from: each document maps to the indexes in self of the maps
+    /// containing it, in ascending order, along with its positions in each of them.
+    fn merge_postings_tagged(self) -> BTreeMap<Id, Vec<(usize, Vec<Position>)>>;
+}
+
+impl<'a, Id: Ord + Clone> PostingsMerge<Id> for &'a [PostingsMap<Id>] {
+    fn merge_postings(self) -> PostingsMap<Id> {
+        self.iter().flat_map(|map| map).collect::<MergePostingsMap<Id>>().0
+    }
+
+    fn merge_postings_tagged(self) -> BTreeMap<Id, Vec<(usize, Vec<Position>)>> {
+        let mut merged = BTreeMap::new();
+        for (term_id, postings) in self.iter().enumerate() {
+            for (doc_id, positions) in postings {
+                merged.entry(doc_id.clone())
+                      .or_insert_with(Vec::new)
+                      .push((term_id, positions.clone()));
+            }
+        }
+        merged
+    }
+}
+
+/// An iterator lazily merging postings, given as iterators over `(doc id, positions)` pairs in
+/// ascending doc id order. It yields each document found in any of the postings once, in
+/// ascending doc id order, with its positions merged, producing the same postings as collecting
+/// into a `MergePostingsMap`. Only the current entry of each of the postings is held at a time.
+pub struct MergePostings<Id, I: Iterator<Item = (Id, Vec<Position>)>> {
+    postings: Vec<Peekable<I>>,
+}
+
+impl<Id: Ord, I: Iterator<Item = (Id, Vec<Position>)>> MergePostings<Id, I> {
+    /// Creates an iterator merging the given postings.
+    pub fn new(postings: Vec<I>) -> MergePostings<Id, I> {
+        MergePostings { postings: postings.into_iter().map(Iterator::peekable).collect() }
+    }
+}
+
+impl<Id: Ord, I: Iterator<Item = (Id, Vec<Position>)>> Iterator for MergePostings<Id, I> {
+    type Item = (Id, Vec<Position>);
+
+    fn next(&mut self) -> Option<(Id, Vec<Position>)> {
+        let min_idx = {
+            let mut min: Option<(usize, &Id)> = None;
+            for (i, postings) in self.postings.iter_mut().enumerate() {
+                if let Some(&(ref doc_id, _)) = postings.peek() {
+                    if min.map_or(true, |(_, min_doc_id)| doc_id < min_doc_id) {
+                        min = Some((i, doc_id));
+                    }
+                }
+            }
+            match min {
+                Some((min_idx, _)) => min_idx,
+                None => return None,
+            }
+        };
+        let (doc_id, mut positions) = self.postings[min_idx].next().unwrap();
+        for postings in &mut self.postings[min_idx + 1..] {
+            if postings.peek().map_or(false, |&(ref other_doc_id, _)| *other_doc_id == doc_id) {
+                let (_, other_positions) = postings.next().unwrap();
+                positions.merge_coalesce(other_positions);
+            }
+        }
+        Some((doc_id, positions))
+    }
+}
+
 /// An extension trait for positionally intersecting two types. A positional intersection is
 /// broadly defined as an intersection in which each element returned is close to an element
 /// not in its own set.
@@ -68,45 +197,42 @@ impl PositionalIntersect for [Position] {
 
     fn intersect_positionally(&self, other: &[Position]) -> Vec<Position> {
         let mut intersection = vec![];
-        let mut this = self.iter().cloned();
-        let mut other = other.iter().cloned();
-        let mut lval = this.next();
-        let mut rval = other.next();
-        loop {
-            if let (Some(l), Some(r)) = (lval, rval) {
-                match l.position.cmp(&r.position) {
-                    Ordering::Less => {
-                        if l.position + 1 == r.position {
-                            if !intersection.is_empty() {
-                                if intersection[intersection.len() - 1] != l {
-                                    intersection.push(l);
-                                }
-                            } else {
-                                intersection.push(l);
-                            }
-                            intersection.push(r);
-                            rval = other.next();
-                        }
-                        lval = this.next();
-                    }
-                    Ordering::Greater | Ordering::Equal => rval = other.next(),
-                }
+        let (mut i, mut j) = (0, 0);
+        while i < self.len() && j < other.len() {
+            let (l, r) = (self[i].position, other[j].position);
+            if l + 1 < r {
+                i += 1;
+            } else if l + 1 > r {
+                j += 1;
             } else {
-                return intersection;
+                // Keep all of the positions at both token positions, since several positions may
+                // share a token position, e.g. in postings merged from several terms.
+                let l_end = i + self[i..].iter().take_while(|p| p.position == l).count();
+                let r_end = j + other[j..].iter().take_while(|p| p.position == r).count();
+                intersection.extend(self[i..l_end].iter().cloned());
+                intersection.extend(other[j..r_end].iter().cloned());
+                i = l_end;
+                j = r_end;
             }
         }
+        // A position matched on the right may also match on the left, e.g. the middle word of
+        // three repeated words.
+        intersection.sort();
+        intersection.dedup();
+        intersection
     }
 }
 
-impl PositionalIntersect for PostingsMap {
-    type Intersection = PostingsMap;
-    fn intersect_positionally(&self, other: &Self) -> PostingsMap {
+impl<Id: Ord + Clone> PositionalIntersect for PostingsMap<Id> {
+    type Intersection = PostingsMap<Id>;
+    fn intersect_positionally(&self, other: &Self) -> PostingsMap<Id> {
         let maps = &[self, other];
         maps.intersection()
             .map(|doc_id| {
                 (doc_id.clone(),
                  self[doc_id].intersect_positionally(&other[doc_id]))
             })
+            .filter(|&(_, ref positions)| !positions.is_empty())
             .collect()
     }
 }
@@ -114,7 +240,10 @@ impl PositionalIntersect for PostingsMap {
 #[cfg(test)]
 mod test {
     use std::iter;
-    use super::super::{MergePostingsMap, Position, PostingsMap};
+    use super::super::{MergePostings, MergePostingsMap, Position, PositionalIntersect,
+                       PostingsIntersect, PostingsMap, PostingsMapExt, PostingsMerge,
+                       SynonymPosition};
+    use util::Coalesce;
 
     #[test]
     fn test_merge() {
@@ -130,4 +259,152 @@ mod test {
                                     Position::new((6, 7), 3)]))
                        .collect());
     }
+
+    fn postings<Id: Ord + Clone>(ids: &[Id], positions: &[usize]) -> PostingsMap<Id> {
+        ids.iter()
+           .map(|id| {
+               (id.clone(),
+                positions.iter()
+                         .map(|&position| {
+                             Position::new((position * 2, position * 2 + 1), position)
+                         })
+                         .collect())
+           })
+           .collect()
+    }
+
+    fn check_intersections<Id: Ord + Clone + ::std::fmt::Debug>(id1: Id, id2: Id, id3: Id) {
+        let first = postings(&[id1.clone(), id2.clone()], &[0, 3]);
+        let second = postings(&[id2.clone(), id3.clone()], &[1, 5]);
+        assert_eq!([first.clone(), second.clone()].intersect_postings(),
+                   postings(&[id2.clone()], &[0, 1, 3, 5]));
+        assert_eq!(first.intersect_positionally(&second),
+                   postings(&[id2.clone()], &[0, 1]));
+        assert_eq!([first.clone(), second.clone()]
+                       .iter()
+                       .flat_map(|map| map)
+                       .collect::<MergePostingsMap<Id>>()
+                       .0
+                       .keys()
+                       .cloned()
+                       .collect::<Vec<_>>(),
+                   vec![id1, id2, id3]);
+    }
+
+    #[test]
+    fn test_usize_ids() {
+        check_intersections(1, 2, 3);
+    }
+
+    #[test]
+    fn test_string_ids() {
+        check_intersections(String::from("a"), String::from("b"), String::from("c"));
+    }
+
+    #[test]
+    fn test_from_positions() {
+        let postings = PostingsMap::from_positions(vec![(2, Position::new((4, 6), 1)),
+                                                        (1, Position::new((0, 3), 0)),
+                                                        (2, Position::new((0, 3), 0)),
+                                                        (2, Position::new((4, 5), 1)),
+                                                        (1, Position::new((0, 3), 0)),
+                                                        (2, Position::new((8, 9), 2))]);
+        assert_eq!(postings.doc_ids().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(postings[&1], vec![Position::new((0, 3), 0)]);
+        assert_eq!(postings[&2],
+                   vec![Position::new((0, 3), 0),
+                        Position::new((4, 6), 1),
+                        Position::new((8, 9), 2)]);
+    }
+
+    #[test]
+    fn test_merge_postings() {
+        let maps = [postings(&[1, 3, 5], &[0, 2]),
+                    postings(&[2, 3], &[1, 2, 4]),
+                    PostingsMap::new(),
+                    postings(&[5, 8], &[7])];
+        let eager = maps.iter().flat_map(|map| map).collect::<MergePostingsMap>().0;
+        let iters = maps.iter().cloned().map(|map| map.into_iter()).collect();
+        let lazy: Vec<_> = MergePostings::new(iters).collect();
+        assert_eq!(lazy, eager.into_iter().collect::<Vec<_>>());
+        assert_eq!(lazy.iter().map(|&(doc_id, _)| doc_id).collect::<Vec<_>>(),
+                   vec![1, 2, 3, 5, 8]);
+        assert_eq!(MergePostings::new(Vec::<::std::vec::IntoIter<(usize, _)>>::new()).next(),
+                   None);
+    }
+
+    #[test]
+    fn test_merge_postings_tagged() {
+        let maps = [postings(&[1, 3], &[0, 2]), PostingsMap::new(), postings(&[3, 4], &[1])];
+        let tagged = maps.merge_postings_tagged();
+        assert_eq!(tagged.keys().cloned().collect::<Vec<_>>(), vec![1, 3, 4]);
+        assert_eq!(tagged[&1], vec![(0, maps[0][&1].clone())]);
+        assert_eq!(tagged[&3], vec![(0, maps[0][&3].clone()), (2, maps[2][&3].clone())]);
+        assert_eq!(tagged[&4], vec![(2, maps[2][&4].clone())]);
+
+        let untagged: PostingsMap = tagged.into_iter()
+                                          .map(|(doc_id, terms)| {
+                                              let mut positions = vec![];
+                                              for (_, term_positions) in terms {
+                                                  positions.merge_coalesce(term_positions);
+                                              }
+                                              (doc_id, positions)
+                                          })
+                                          .collect();
+        assert_eq!(untagged, maps.merge_postings());
+    }
+
+    #[test]
+    fn test_intersect_postings() {
+        assert_eq!((&[] as &[PostingsMap]).intersect_postings(), PostingsMap::new());
+
+        let single = postings(&[1, 2], &[0, 3]);
+        assert_eq!([single.clone()].intersect_postings(), single);
+
+        let maps = [postings(&[1, 2, 3], &[0]),
+                    postings(&[2, 3, 4], &[1, 5]),
+                    postings(&[3, 2], &[0, 2])];
+        assert_eq!(maps.intersect_postings(), postings(&[2, 3], &[0, 1, 2, 5]));
+        let disjoint = [postings(&[1], &[0]), postings(&[2], &[0]), postings(&[1, 2], &[0])];
+        assert_eq!(disjoint.intersect_postings(), PostingsMap::new());
+    }
+
+    #[test]
+    fn test_synonym_position_merge() {
+        let car = Position::new((4, 7), 1);
+        let automobile = Position::new((4, 7), 2);
+        let mut positions = vec![car];
+        positions.search_coalesce(0, automobile);
+        assert_eq!(positions, vec![car, automobile]);
+
+        let mut positions = vec![SynonymPosition(car)];
+        positions.search_coalesce(0, SynonymPosition(automobile));
+        positions.search_coalesce(0, SynonymPosition(Position::new((5, 9), 3)));
+        positions.search_coalesce(0, SynonymPosition(Position::new((10, 12), 4)));
+        assert_eq!(positions,
+                   vec![SynonymPosition(Position::new((4, 9), 1)),
+                        SynonymPosition(Position::new((10, 12), 4))]);
+    }
+
+    #[test]
+    fn test_intersect_positionally_repeated() {
+        let words = [Position::new((0, 2), 0), Position::new((3, 5), 1), Position::new((6, 8), 2)];
+        assert_eq!(words.intersect_positionally(&words), words);
+        // Several positions at each token position, as in postings merged from several terms
+        let first = [Position::new((0, 1), 0),
+                     Position::new((0, 2), 0),
+                     Position::new((3, 4), 1),
+                     Position::new((3, 5), 1)];
+        let second = [Position::new((3, 4), 1),
+                      Position::new((3, 5), 1),
+                      Position::new((6, 7), 2),
+                      Position::new((6, 8), 2)];
+        assert_eq!(first.intersect_positionally(&second),
+                   [Position::new((0, 1), 0),
+                    Position::new((0, 2), 0),
+                    Position::new((3, 4), 1),
+                    Position::new((3, 5), 1),
+                    Position::new((6, 7), 2),
+                    Position::new((6, 8), 2)]);
+    }
 }
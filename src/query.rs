@@ -1,3 +1,8 @@
+use std::error;
+use std::fmt;
+
+use util::{damerau_levenshtein, levenshtein};
+
 /// A Query organizes a search of an inverted index.
 /// It is recursively hierarchical, allowing flexibility
 /// in exactly how a search is specified
@@ -15,13 +20,396 @@ pub enum Query<'a> {
     /// in each sub-query
     Or(&'a [Query<'a>]),
 
-    /// An exact-match query. The given phrase must appear in all documents returned.
-    /// False positives may occur.
+    /// An exact-match query. The given phrase must appear in all documents returned, and only the
+    /// positions of its occurrences count toward the score. Each of the phrase's terms must match
+    /// a whole word in the document, not just a prefix of one.
     Phrase(&'a str),
 
     /// A prefix query that returns all documents containing terms with the given prefix.
     /// Note that, unlike `Match` and `Phrase`, this query is not tokenized before searching
     /// the index. Thus, Prefix("hi bob") is likely to match zero documents, since indexed
     /// documents typically have their content tokenized upon spaces.
+    /// When run as a top-level query, terms that more closely match the whole prefix rank higher.
     Prefix(&'a str),
+
+    /// A query for a single term, looked up in the index verbatim. Unlike `Match`, the term is
+    /// not analyzed (e.g. lowercased or split on whitespace) first, so it should already be
+    /// normalized the way indexed terms are.
+    Term(&'a str),
+
+    /// A phrase query whose last term is treated as a prefix, e.g. for search-as-you-type.
+    /// All terms but the last must appear in order as whole words, as in a `Phrase` query,
+    /// immediately followed by a word starting with the last term.
+    PhrasePrefix(&'a str),
+
+    /// A query for documents whose content contains the given string verbatim, including case,
+    /// punctuation and whitespace. Candidates are found through the index, then verified by
+    /// scanning their content, so there are no false positives.
+    Literal(&'a str),
+
+    /// A query for documents containing words within the given edit distance of the words of
+    /// the given string, e.g. to tolerate typos. Like `Match`, the string is analyzed first.
+    /// How the distance is measured is configured on the index with `set_fuzzy_kind`.
+    Fuzzy(&'a str, usize),
+
+    /// A proximity query. The words of the given string must appear in order as whole words, as in
+    /// a `Phrase` query, but with up to the given number of other words, in total, between them.
+    /// When run as a top-level query, tighter matches score higher: each result's score is
+    /// multiplied by `1 / (1 + gap)`, where `gap` is the fewest words between the terms in any of
+    /// the document's matches.
+    Near(&'a str, usize),
+
+    /// A boolean query combining required, optional and prohibited sub-queries. Documents must
+    /// match all of the `must` queries and none of the `must_not` queries; matching `should`
+    /// queries isn't required, but their matches add to the score. If there are no `must`
+    /// queries, documents must match at least one of the `should` queries instead.
+    Boolean {
+        /// The sub-queries that documents must match.
+        must: &'a [Query<'a>],
+        /// The sub-queries whose matches boost documents' scores.
+        should: &'a [Query<'a>],
+        /// The sub-queries that documents must not match.
+        must_not: &'a [Query<'a>],
+    },
+
+    /// A query matching the same documents as the given query, but whose positions span the
+    /// whole words they fall in, rather than just the matching prefixes of words, e.g. for
+    /// external highlighters that expect whole-word spans. Each position's token position and
+    /// byte offsets locate a matched word; a word matched several times is reported once. Scores
+    /// are computed from the widened positions.
+    Spanned(&'a Query<'a>),
+
+    /// A query for documents containing a number between the given minimum and maximum,
+    /// inclusive, e.g. a year or a price. Only numbers indexed while `set_index_numbers` was
+    /// enabled are searched. Results have no positions, so they aren't highlighted, and all score
+    /// 0.
+    NumericRange(i64, i64),
+}
+
+impl<'a> Query<'a> {
+    /// Returns the query's text, for queries searching for text rather than combining
+    /// sub-queries.
+    fn text(&self) -> Option<&'a str> {
+        match *self {
+            Query::Match(text) | Query::Phrase(text) | Query::Prefix(text) | Query::Term(text) |
+            Query::PhrasePrefix(text) | Query::Literal(text) | Query::Fuzzy(text, _) |
+            Query::Near(text, _) => Some(text),
+            Query::And(_) | Query::Or(_) | Query::Boolean { .. } | Query::Spanned(_) |
+            Query::NumericRange(..) => None,
+        }
+    }
+
+    /// Returns whether the query is a text query that can't match any document, because its
+    /// text is empty, or blank for queries other than `Literal`.
+    fn is_dead(&self) -> bool {
+        match (*self, self.text()) {
+            (Query::Literal(text), _) => text.is_empty(),
+            (_, Some(text)) => text.trim().is_empty(),
+            (_, None) => false,
+        }
+    }
+
+    /// Checks that the query is well-formed: that none of its text queries are empty (or blank,
+    /// for queries other than `Literal`), and none of its `And` and `Or` queries have no
+    /// sub-queries, nor its `Boolean` queries no `must` or `should` sub-queries. Such queries
+    /// can't match any document, which is likely a mistake.
+    pub fn validate(&self) -> Result<(), QueryError> {
+        match *self {
+            Query::And(queries) | Query::Or(queries) => {
+                if queries.is_empty() {
+                    return Err(QueryError::NoSubQueries);
+                }
+                for query in queries {
+                    try!(query.validate());
+                }
+                Ok(())
+            }
+            Query::Boolean { must, should, must_not } => {
+                if must.is_empty() && should.is_empty() {
+                    return Err(QueryError::NoSubQueries);
+                }
+                for query in must.iter().chain(should).chain(must_not) {
+                    try!(query.validate());
+                }
+                Ok(())
+            }
+            Query::Spanned(query) => query.validate(),
+            _ if self.is_dead() => Err(QueryError::EmptyText),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an equivalent query, simplified: nested `And` queries are flattened into a single
+    /// `And`, and likewise for `Or`; `And` and `Or` queries with a single sub-query are replaced
+    /// by it; and clauses that can't match any document are removed. A query that can't match
+    /// any document at all is normalized to an `Or` with no sub-queries.
+    pub fn normalize(&self) -> NormalizedQuery<'a> {
+        match *self {
+            Query::And(queries) => {
+                let mut normalized = vec![];
+                for query in queries {
+                    match query.normalize() {
+                        NormalizedQuery::And(queries) => normalized.extend(queries),
+                        ref query if query.is_dead() => return NormalizedQuery::dead(),
+                        query => normalized.push(query),
+                    }
+                }
+                if normalized.is_empty() {
+                    return NormalizedQuery::dead();
+                }
+                NormalizedQuery::simplify(normalized, NormalizedQuery::And)
+            }
+            Query::Or(queries) => {
+                let mut normalized = vec![];
+                for query in queries {
+                    match query.normalize() {
+                        NormalizedQuery::Or(queries) => normalized.extend(queries),
+                        query => normalized.push(query),
+                    }
+                }
+                NormalizedQuery::simplify(normalized, NormalizedQuery::Or)
+            }
+            Query::Boolean { must, should, must_not } => {
+                let normalize_all = |queries: &[Query<'a>]| -> Vec<NormalizedQuery<'a>> {
+                    queries.iter()
+                           .map(Query::normalize)
+                           .filter(|query| !query.is_dead())
+                           .collect()
+                };
+                let must: Vec<_> = must.iter().map(Query::normalize).collect();
+                if must.iter().any(NormalizedQuery::is_dead) {
+                    return NormalizedQuery::dead();
+                }
+                let should = normalize_all(should);
+                if must.is_empty() && should.is_empty() {
+                    return NormalizedQuery::dead();
+                }
+                NormalizedQuery::Boolean {
+                    must: must,
+                    should: should,
+                    must_not: normalize_all(must_not),
+                }
+            }
+            Query::Spanned(query) if query.normalize().is_dead() => NormalizedQuery::dead(),
+            query if query.is_dead() => NormalizedQuery::dead(),
+            query => NormalizedQuery::Leaf(query),
+        }
+    }
+}
+
+/// An owned query tree, as returned by `Query::normalize`. Its text queries borrow their text
+/// from the query it was normalized from. Use `with_query` to run it.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum NormalizedQuery<'a> {
+    /// A query other than `And`, `Or` and `Boolean`. `Spanned` queries are left as is.
+    Leaf(Query<'a>),
+    /// An `And` of the given sub-queries.
+    And(Vec<NormalizedQuery<'a>>),
+    /// An `Or` of the given sub-queries. An `Or` without sub-queries matches nothing.
+    Or(Vec<NormalizedQuery<'a>>),
+    /// A `Boolean` query of the given sub-queries.
+    Boolean {
+        /// The sub-queries that documents must match.
+        must: Vec<NormalizedQuery<'a>>,
+        /// The sub-queries whose matches boost documents' scores.
+        should: Vec<NormalizedQuery<'a>>,
+        /// The sub-queries that documents must not match.
+        must_not: Vec<NormalizedQuery<'a>>,
+    },
+}
+
+impl<'a> NormalizedQuery<'a> {
+    /// Calls `f` with the equivalent `Query`, e.g. to pass it to `InvertedIndex::query`, and
+    /// returns its result.
+    pub fn with_query<R, F>(&self, f: F) -> R
+        where F: FnOnce(&Query) -> R
+    {
+        let mut f = Some(f);
+        let mut result = None;
+        self.visit(&mut |query| result = f.take().map(|f| f(query)));
+        result.unwrap()
+    }
+
+    /// Calls `f` with the equivalent `Query`, whose sub-query slices live on the stack.
+    fn visit(&self, f: &mut FnMut(&Query)) {
+        match *self {
+            NormalizedQuery::Leaf(query) => f(&query),
+            NormalizedQuery::And(ref queries) => {
+                visit_all(queries, vec![], &mut |queries| f(&Query::And(queries)))
+            }
+            NormalizedQuery::Or(ref queries) => {
+                visit_all(queries, vec![], &mut |queries| f(&Query::Or(queries)))
+            }
+            NormalizedQuery::Boolean { ref must, ref should, ref must_not } => {
+                visit_all(must, vec![], &mut |must| {
+                    visit_all(should, vec![], &mut |should| {
+                        visit_all(must_not, vec![], &mut |must_not| {
+                            f(&Query::Boolean {
+                                must: must,
+                                should: should,
+                                must_not: must_not,
+                            })
+                        })
+                    })
+                })
+            }
+        }
+    }
+
+    /// The normalized form of queries that can't match any document.
+    fn dead() -> NormalizedQuery<'a> {
+        NormalizedQuery::Or(vec![])
+    }
+
+    fn is_dead(&self) -> bool {
+        *self == NormalizedQuery::dead()
+    }
+
+    /// Replaces a compound query of a single sub-query with the sub-query.
+    fn simplify<F>(mut queries: Vec<NormalizedQuery<'a>>, compound: F) -> NormalizedQuery<'a>
+        where F: FnOnce(Vec<NormalizedQuery<'a>>) -> NormalizedQuery<'a>
+    {
+        if queries.len() == 1 {
+            queries.pop().unwrap()
+        } else {
+            compound(queries)
+        }
+    }
+}
+
+/// Calls `f` with the `Query`s equivalent to `queries`, appended to `visited`.
+fn visit_all(queries: &[NormalizedQuery], visited: Vec<Query>, f: &mut FnMut(&[Query])) {
+    match queries.split_first() {
+        None => f(&visited),
+        Some((first, rest)) => {
+            first.visit(&mut |query| {
+                let mut visited: Vec<Query> = visited.clone();
+                visited.push(*query);
+                visit_all(rest, visited, f)
+            })
+        }
+    }
+}
+
+/// An error describing why a query is malformed, as returned by `Query::validate`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueryError {
+    /// A text query has no text to search for.
+    EmptyText,
+    /// An `And` or `Or` query has no sub-queries, or a `Boolean` query has neither `must` nor
+    /// `should` sub-queries.
+    NoSubQueries,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+impl error::Error for QueryError {
+    fn description(&self) -> &str {
+        match *self {
+            QueryError::EmptyText => "query has no text to search for",
+            QueryError::NoSubQueries => "compound query has no sub-queries",
+        }
+    }
+}
+
+/// The measure of edit distance used by `Fuzzy` queries.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, RustcDecodable, RustcEncodable)]
+pub enum FuzzyKind {
+    /// The Levenshtein distance, counting char insertions, deletions and substitutions.
+    Levenshtein,
+    /// The Damerau-Levenshtein distance, which also counts swapping two adjacent chars, e.g.
+    /// "teh" for "the", as a single edit.
+    Damerau,
+}
+
+impl Default for FuzzyKind {
+    fn default() -> FuzzyKind {
+        FuzzyKind::Levenshtein
+    }
+}
+
+impl FuzzyKind {
+    /// Returns the distance between the strings.
+    pub fn distance(self, a: &str, b: &str) -> usize {
+        match self {
+            FuzzyKind::Levenshtein => levenshtein(a, b),
+            FuzzyKind::Damerau => damerau_levenshtein(a, b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NormalizedQuery, QueryError};
+    use Query::*;
+
+    #[test]
+    fn normalize_collapses_single_sub_queries() {
+        assert_eq!(And(&[And(&[Match("a")])]).normalize(),
+                   NormalizedQuery::Leaf(Match("a")));
+        assert_eq!(Or(&[And(&[Phrase("a b")])]).normalize(),
+                   NormalizedQuery::Leaf(Phrase("a b")));
+    }
+
+    #[test]
+    fn normalize_flattens() {
+        let query = Or(&[Or(&[Match("a"), Or(&[Match("b")])]), Match("c"), And(&[Match("d")])]);
+        let normalized = query.normalize();
+        assert_eq!(normalized,
+                   NormalizedQuery::Or(vec![NormalizedQuery::Leaf(Match("a")),
+                                            NormalizedQuery::Leaf(Match("b")),
+                                            NormalizedQuery::Leaf(Match("c")),
+                                            NormalizedQuery::Leaf(Match("d"))]));
+        assert!(normalized.with_query(|query| {
+            *query == Or(&[Match("a"), Match("b"), Match("c"), Match("d")])
+        }));
+        let query = And(&[And(&[Match("a"), Match("b")]), Or(&[Match("c"), Match("d")])]);
+        assert!(query.normalize().with_query(|query| {
+            *query == And(&[Match("a"), Match("b"), Or(&[Match("c"), Match("d")])])
+        }));
+    }
+
+    #[test]
+    fn normalize_removes_dead_clauses() {
+        assert_eq!(Or(&[Match(""), Match("a"), Prefix(" ")]).normalize(),
+                   NormalizedQuery::Leaf(Match("a")));
+        assert_eq!(And(&[Match("a"), Or(&[Phrase(" ")])]).normalize(),
+                   NormalizedQuery::Or(vec![]));
+        assert_eq!(Literal(" ").normalize(), NormalizedQuery::Leaf(Literal(" ")));
+        assert_eq!(Or(&[And(&[]), Match("a")]).normalize(), NormalizedQuery::Leaf(Match("a")));
+        let query = Boolean {
+            must: &[And(&[Match("a")])],
+            should: &[Match(""), Match("b")],
+            must_not: &[Term("")],
+        };
+        assert!(query.normalize().with_query(|query| {
+            *query ==
+            Boolean {
+                must: &[Match("a")],
+                should: &[Match("b")],
+                must_not: &[],
+            }
+        }));
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(And(&[Match("a"), Or(&[Phrase("b c"), Prefix("d")])]).validate(), Ok(()));
+        assert_eq!(And(&[Match("a"), Or(&[Match(" ")])]).validate(),
+                   Err(QueryError::EmptyText));
+        assert_eq!(Or(&[Match("a"), And(&[])]).validate(), Err(QueryError::NoSubQueries));
+        assert_eq!(Literal(" ").validate(), Ok(()));
+        assert_eq!(Literal("").validate(), Err(QueryError::EmptyText));
+        assert_eq!(Boolean {
+                       must: &[],
+                       should: &[],
+                       must_not: &[Match("a")],
+                   }
+                       .validate(),
+                   Err(QueryError::NoSubQueries));
+    }
 }
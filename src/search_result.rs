@@ -1,4 +1,12 @@
+use std::cmp::{self, Ordering};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+
 use super::{Document, Position};
+use util::Coalesce;
 
 /// A SearchResult is the representation of a Document returned for a specific set of search
 /// terms. It is unique upon the document and the vec of highlight indices. It also contains a
@@ -11,6 +19,11 @@ pub struct SearchResult<'a> {
     pub positions: Vec<Position>,
     /// The search score, for use in ranking documents
     pub score: f32,
+    /// For results of `InvertedIndex::query_with_terms`, the query term that produced each
+    /// position, parallel to `positions`. Terms are identified by the index of the leaf query
+    /// that matched them, numbering the query's leaves (e.g. `Match` and `Phrase` queries) from
+    /// left to right, starting at 0. `None` for results of other searches.
+    pub terms: Option<Vec<usize>>,
 }
 
 impl<'a> SearchResult<'a> {
@@ -18,12 +31,43 @@ impl<'a> SearchResult<'a> {
     /// Computes the score using the positions and the document length
     pub fn new(doc: &'a Document, positions: Vec<Position>) -> SearchResult<'a> {
         SearchResult {
-            score: positions.iter()
-                            .map(|&Position { offsets: (begin, end), .. }| end - begin)
-                            .sum::<usize>() as f32 /
-                   (doc.content().len() as f32).sqrt(),
+            score: score(doc, &positions),
             doc: doc,
             positions: positions,
+            terms: None,
+        }
+    }
+
+    /// Returns an owned copy of the search result, whose document's content is truncated to at
+    /// most `max_content` bytes, at a char boundary, e.g. to limit the size of API responses. The
+    /// positions, which were computed from the full content, are kept where they fall within the
+    /// truncated content, and clamped to its end where they straddle it.
+    pub fn to_owned_truncated(&self, max_content: usize) -> OwnedSearchResult {
+        let content = self.doc.content();
+        let mut len = cmp::min(max_content, content.len());
+        while !content.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut doc = self.doc.clone();
+        doc.truncate(len);
+        let mut positions = vec![];
+        let mut terms = self.terms.as_ref().map(|_| vec![]);
+        for (i, &position) in self.positions.iter().enumerate() {
+            let (begin, end) = position.offsets;
+            if begin >= len {
+                continue;
+            }
+            positions.push(Position { offsets: (begin, cmp::min(end, len)), ..position });
+            if let (Some(truncated), Some(terms)) = (terms.as_mut(), self.terms.as_ref()) {
+                truncated.push(terms[i]);
+            }
+        }
+        OwnedSearchResult {
+            truncated: len < content.len(),
+            doc: doc,
+            positions: positions,
+            score: self.score,
+            terms: terms,
         }
     }
 
@@ -32,6 +76,11 @@ impl<'a> SearchResult<'a> {
         &self.doc
     }
 
+    /// Returns the document's metadata, if it has any, e.g. to render the result's title.
+    pub fn metadata(&self) -> Option<&str> {
+        self.doc.metadata()
+    }
+
     /// Returns the highlighted indices.
     ///
     /// Each `(usize, usize)` indicates the start and end of a term in the document's content
@@ -40,28 +89,610 @@ impl<'a> SearchResult<'a> {
         &self.positions
     }
 
+    /// Returns the highlighted positions grouped by their token position, i.e. by the index of the
+    /// word they matched in the document.
+    pub fn positions_by_word(&self) -> BTreeMap<usize, Vec<Position>> {
+        let mut by_word = BTreeMap::new();
+        for &position in &self.positions {
+            by_word.entry(position.position).or_insert_with(Vec::new).push(position);
+        }
+        by_word
+    }
+
+    /// Returns the unique substrings of the document's content that matched the search, in the
+    /// order they first occur. Overlapping positions are coalesced, so that each substring is a
+    /// maximal matching span rather than, e.g., each of the prefixes of a matching word. Positions
+    /// that don't fit the content are clamped or skipped, as by `highlight`.
+    pub fn matched_terms(&self) -> Vec<&str> {
+        let content = self.doc.content();
+        let mut offsets: Vec<_> = self.positions.iter().map(|position| position.offsets).collect();
+        offsets.sort();
+        let mut spans = Vec::with_capacity(offsets.len());
+        for span in offsets {
+            let len = spans.len();
+            spans.coalesce(len, span);
+        }
+        let mut terms = vec![];
+        for (begin, end) in spans {
+            let end = cmp::min(end, content.len());
+            if begin >= end || !content.is_char_boundary(begin) || !content.is_char_boundary(end) {
+                continue;
+            }
+            let term = &content[begin..end];
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+        terms
+    }
+
     /// Returns the search result's score.
     ///
     /// Score is computed by the product of the summed length of the matching terms and the inverse
     /// square root of the length of the document. Taking the square root of the document's length
-    /// helps to combat bias toward short content.
+    /// helps to combat bias toward short content. Indexes scoring by `ScoringMode::Raw` omit the
+    /// document's length.
     pub fn score(&self) -> f32 {
         self.score
     }
 
     /// Returns the search result's content, surrounding all highlighted terms with `before`
     /// and `after` 
+    ///
+    /// Positions that don't fit the document's content, e.g. positions computed for a stale
+    /// version of the document, are clamped to the end of the content, or skipped entirely if
+    /// they lie outside of it or don't fall on char boundaries. Zero-width positions are skipped
+    /// too, rather than producing empty highlights.
     pub fn highlight(&self, before: &str, after: &str) -> String {
-        let mut begin_idx = 0;
         let mut parts = String::new();
+        self.each_segment(|segment, matched| {
+            if matched {
+                parts.push_str(before);
+                parts.push_str(segment);
+                parts.push_str(after);
+            } else {
+                parts.push_str(segment);
+            }
+        });
+        parts
+    }
+
+    /// Returns the search result's content, surrounding at most `n` highlighted terms with `before`
+    /// and `after`, as in `highlight`. The longest terms are highlighted, the earliest first among
+    /// terms of equal length; the rest of the content is passed through unhighlighted.
+    pub fn highlight_top_n(&self, before: &str, after: &str, n: usize) -> String {
+        let mut top = self.positions.clone();
+        top.sort_by(|pos1, pos2| {
+            let len1 = pos1.offsets.1.saturating_sub(pos1.offsets.0);
+            let len2 = pos2.offsets.1.saturating_sub(pos2.offsets.0);
+            match len2.cmp(&len1) {
+                Ordering::Equal => pos1.offsets.cmp(&pos2.offsets),
+                ordering => ordering,
+            }
+        });
+        top.truncate(n);
+        top.sort();
+        SearchResult::new(self.doc, top).highlight(before, after)
+    }
+
+    /// Writes the search result's content to `w`, surrounding all highlighted terms with `before`
+    /// and `after`, as in `highlight`, but without building the highlighted content in memory.
+    /// Stops writing at the first error, which is returned.
+    pub fn highlight_to<W>(&self, w: &mut W, before: &str, after: &str) -> io::Result<()>
+        where W: Write
+    {
+        let mut result = Ok(());
+        self.each_segment(|segment, matched| {
+            if result.is_err() {
+                return;
+            }
+            result = if matched {
+                write!(w, "{}{}{}", before, segment, after)
+            } else {
+                w.write_all(segment.as_bytes())
+            };
+        });
+        result
+    }
+
+    /// Returns the search result's content as HTML, wrapping each highlighted term in a `tag`
+    /// element with a `data-match` attribute numbering the matches from 0, e.g.
+    /// `<mark data-match="0">`. The content is HTML-escaped.
+    pub fn highlight_numbered(&self, tag: &str) -> String {
+        let mut parts = String::new();
+        let mut match_number = 0;
+        self.each_segment(|segment, matched| {
+            if matched {
+                parts.push_str(&format!("<{} data-match=\"{}\">", tag, match_number));
+                escape_html(segment, &mut parts);
+                parts.push_str(&format!("</{}>", tag));
+                match_number += 1;
+            } else {
+                escape_html(segment, &mut parts);
+            }
+        });
+        parts
+    }
+
+    /// Calls `f` on each consecutive segment of the document's content, along with whether the
+    /// segment is a highlighted term. Positions that don't fit the content are clamped or skipped,
+    /// and zero-width positions are skipped.
+    fn each_segment<F>(&self, f: F)
+        where F: FnMut(&str, bool)
+    {
+        self.each_segment_in(0, self.doc.content().len(), f)
+    }
+
+    /// Like `each_segment`, but only for the segments between the byte offsets `start` and `stop`
+    /// of the content, which must be char boundaries.
+    fn each_segment_in<F>(&self, start: usize, stop: usize, mut f: F)
+        where F: FnMut(&str, bool)
+    {
+        let content = &self.doc.content()[..stop];
+        let mut begin_idx = start;
         for &Position{offsets:(begin, end), ..} in &self.positions {
-            parts.push_str(&self.doc.content()[begin_idx..begin]);
-            parts.push_str(before);
-            parts.push_str(&self.doc.content()[begin..end]);
-            parts.push_str(after);
+            let end = cmp::min(end, content.len());
+            if begin < begin_idx || begin >= end || !content.is_char_boundary(begin) ||
+               !content.is_char_boundary(end) {
+                continue;
+            }
+            if begin_idx < begin {
+                f(&content[begin_idx..begin], false);
+            }
+            f(&content[begin..end], true);
             begin_idx = end;
         }
-        parts.push_str(&self.doc.content()[begin_idx..]);
-        parts
+        if begin_idx < content.len() {
+            f(&content[begin_idx..], false);
+        }
+    }
+
+    /// Returns the sentences of the document's content containing highlighted terms, surrounding
+    /// the terms with `before` and `after` as in `highlight`, and joined by "…". Sentences end at
+    /// '.', '!', '?' and newlines; sentences containing several terms are included once.
+    pub fn sentence_snippet(&self, before: &str, after: &str) -> String {
+        let content = self.doc.content();
+        let mut sentences: Vec<(usize, usize)> = vec![];
+        for &Position { offsets: (begin, end), .. } in &self.positions {
+            let end = cmp::min(end, content.len());
+            if begin >= end || !content.is_char_boundary(begin) || !content.is_char_boundary(end) {
+                continue;
+            }
+            let start = content[..begin].rfind(is_sentence_end).map_or(0, |i| i + 1);
+            let start = content.len() - content[start..].trim_left().len();
+            let stop = match content[end..].find(is_sentence_end) {
+                Some(i) if content[end + i..].starts_with('\n') => end + i,
+                Some(i) => end + i + 1,
+                None => content.len(),
+            };
+            if let Some(last) = sentences.last_mut() {
+                if start < last.1 {
+                    last.1 = cmp::max(last.1, stop);
+                    continue;
+                }
+            }
+            sentences.push((start, stop));
+        }
+
+        let mut snippet = String::new();
+        for (i, &(start, stop)) in sentences.iter().enumerate() {
+            if i > 0 {
+                snippet.push('…');
+            }
+            self.each_segment_in(start, stop, |segment, matched| {
+                if matched {
+                    snippet.push_str(before);
+                    snippet.push_str(segment);
+                    snippet.push_str(after);
+                } else {
+                    snippet.push_str(segment);
+                }
+            });
+        }
+        snippet
+    }
+}
+
+/// Displays the search result's content with its highlighted terms in square brackets, e.g.
+/// `learn to [prog]ram`, for debugging and logging.
+impl<'a> fmt::Display for SearchResult<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.highlight("[", "]"))
+    }
+}
+
+/// A search result that owns its document, as returned by `InvertedIndex::query_truncated`.
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct OwnedSearchResult {
+    /// The document returned for the search, whose content may have been truncated
+    pub doc: Document,
+    /// The positions of the terms in the document that matched the search
+    pub positions: Vec<Position>,
+    /// The search score, for use in ranking documents
+    pub score: f32,
+    /// The query term that produced each position, as in `SearchResult::terms`
+    pub terms: Option<Vec<usize>>,
+    /// Whether the document's content was truncated
+    pub truncated: bool,
+}
+
+impl OwnedSearchResult {
+    /// Returns a `SearchResult` borrowing the owned result, e.g. to highlight it.
+    pub fn as_search_result(&self) -> SearchResult {
+        SearchResult {
+            doc: &self.doc,
+            positions: self.positions.clone(),
+            score: self.score,
+            terms: self.terms.clone(),
+        }
+    }
+
+    /// Returns the search result's content, surrounding all highlighted terms with `before`
+    /// and `after`, as in `SearchResult::highlight`.
+    pub fn highlight(&self, before: &str, after: &str) -> String {
+        self.as_search_result().highlight(before, after)
+    }
+}
+
+/// Returns whether the char ends a sentence.
+fn is_sentence_end(c: char) -> bool {
+    c == '.' || c == '!' || c == '?' || c == '\n'
+}
+
+/// Appends `s` to `out`, escaping the characters that are special in HTML.
+fn escape_html(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Computes the search score of a document matching at the given positions: the summed length of
+/// the matching terms divided by the square root of the document's length.
+pub fn score(doc: &Document, positions: &[Position]) -> f32 {
+    ScoringMode::default().score(doc, positions)
+}
+
+/// An `f32` that's compared, ordered and hashed by its bits, like `f32::total_cmp`, so that the
+/// floats held by an index don't keep it from deriving `Eq`, `Ord` and `Hash`, and scores can be
+/// sorted even if they're NaN.
+#[derive(Clone, Copy, Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct TotalF32(pub f32);
+
+impl TotalF32 {
+    /// Returns the float's bits, with the magnitude bits of negative floats flipped so that the
+    /// bits order the same as the floats.
+    fn key(self) -> i32 {
+        let bits = self.0.to_bits() as i32;
+        bits ^ (((bits >> 31) as u32) >> 1) as i32
+    }
+}
+
+impl PartialEq for TotalF32 {
+    fn eq(&self, other: &TotalF32) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for TotalF32 {}
+
+impl PartialOrd for TotalF32 {
+    fn partial_cmp(&self, other: &TotalF32) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TotalF32 {
+    fn cmp(&self, other: &TotalF32) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Hash for TotalF32 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+/// Orders `(score, tiebreaker)` pairs by descending score, then by ascending tiebreaker.
+pub fn compare_results<K>((score1, key1): (f32, K), (score2, key2): (f32, K)) -> Ordering
+    where K: Ord
+{
+    match TotalF32(score2).cmp(&TotalF32(score1)) {
+        Ordering::Equal => key1.cmp(&key2),
+        ordering => ordering,
+    }
+}
+
+/// Sorts search results by descending score, then by ascending doc id.
+pub fn sort_results(results: &mut Vec<SearchResult>) {
+    results.sort_by(|result1, result2| {
+        compare_results((result1.score, result1.doc.id), (result2.score, result2.doc.id))
+    });
+}
+
+/// Merges lists of search results, e.g. from searching several indexes holding different shards
+/// of a corpus, into a single list sorted by descending score, then by ascending doc id, and
+/// truncated to at most `limit` results. Results for the same doc id are deduplicated, keeping
+/// the one with the highest score.
+pub fn merge_results<'a>(lists: Vec<Vec<SearchResult<'a>>>, limit: usize) -> Vec<SearchResult<'a>> {
+    let mut merged: BTreeMap<usize, SearchResult<'a>> = BTreeMap::new();
+    for result in lists.into_iter().flat_map(|list| list) {
+        match merged.entry(result.doc.id) {
+            Entry::Vacant(entry) => {
+                entry.insert(result);
+            }
+            Entry::Occupied(mut entry) => {
+                if result.score > entry.get().score {
+                    entry.insert(result);
+                }
+            }
+        }
+    }
+    let mut results: Vec<_> = merged.into_iter().map(|(_, result)| result).collect();
+    sort_results(&mut results);
+    results.truncate(limit);
+    results
+}
+
+/// How the score of a document matching a search is computed from the length of its matching
+/// content.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, RustcDecodable, RustcEncodable)]
+pub enum ScoringMode {
+    /// The summed length of the matching terms divided by the given function of the document's
+    /// length, to combat bias toward long documents.
+    LengthNormalized(LengthNorm),
+    /// The summed length of the matching terms, ignoring the document's length. This suits
+    /// corpora of documents of roughly equal length, where length normalization is just noise.
+    Raw,
+}
+
+impl Default for ScoringMode {
+    fn default() -> ScoringMode {
+        ScoringMode::LengthNormalized(LengthNorm::Sqrt)
+    }
+}
+
+impl ScoringMode {
+    /// Computes the search score of a document matching at the given positions.
+    pub fn score(self, doc: &Document, positions: &[Position]) -> f32 {
+        self.score_by(self.length_norm(doc.content().len()), positions)
+    }
+
+    /// Computes the search score of a document matching at the given positions, given the
+    /// document's length norm, e.g. as precomputed when the document was indexed.
+    pub fn score_by(self, length_norm: f32, positions: &[Position]) -> f32 {
+        let matched = positions.iter()
+                               .map(|&Position { offsets: (begin, end), .. }| {
+                                   end.saturating_sub(begin)
+                               })
+                               .sum::<usize>();
+        self.normalize_by(length_norm, matched as f32)
+    }
+
+    /// Scales the length of a document's matching content into its score.
+    pub fn normalize(self, doc: &Document, matched: f32) -> f32 {
+        self.normalize_by(self.length_norm(doc.content().len()), matched)
+    }
+
+    /// Scales the length of a document's matching content into its score, given the document's
+    /// length norm, as returned by `length_norm`.
+    pub fn normalize_by(self, length_norm: f32, matched: f32) -> f32 {
+        match self {
+            ScoringMode::LengthNormalized(_) => matched / length_norm,
+            ScoringMode::Raw => matched,
+        }
+    }
+
+    /// Returns the norm of a document of the given length, in bytes, by which the length of its
+    /// matching content is divided. `Raw` scores aren't divided, so their norm is 1.
+    pub fn length_norm(self, len: usize) -> f32 {
+        match self {
+            ScoringMode::LengthNormalized(norm) => norm.norm(len),
+            ScoringMode::Raw => 1.,
+        }
+    }
+}
+
+/// The function of a document's length by which `ScoringMode::LengthNormalized` scores are
+/// divided. The stronger the normalization, the less long documents are favored for matching
+/// more content. There is no norm for leaving scores unnormalized: that's `ScoringMode::Raw`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, RustcDecodable, RustcEncodable)]
+pub enum LengthNorm {
+    /// The document's length.
+    Linear,
+    /// The square root of the document's length.
+    Sqrt,
+    /// The natural logarithm of one plus the document's length.
+    Log,
+}
+
+impl Default for LengthNorm {
+    fn default() -> LengthNorm {
+        LengthNorm::Sqrt
+    }
+}
+
+impl LengthNorm {
+    /// Returns the norm of a document of the given length, in bytes.
+    pub fn norm(self, len: usize) -> f32 {
+        let len = len as f32;
+        match self {
+            LengthNorm::Linear => len,
+            LengthNorm::Sqrt => len.sqrt(),
+            LengthNorm::Log => len.ln_1p(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32;
+
+    use super::SearchResult;
+    use super::super::{Document, Position};
+
+    #[test]
+    fn highlight_out_of_range() {
+        let doc = Document::new(1, "rust is fun");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 4), 0),
+                                                   Position::new((10, 50), 2),
+                                                   Position::new((100, 105), 3)]);
+        assert_eq!(search_result.highlight("*", "*"), "*rust* is fu*n*");
+    }
+
+    #[test]
+    fn highlight_top_n() {
+        let doc = Document::new(1, "rust a rusty rust rustacean");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 4), 0),
+                                                   Position::new((7, 12), 2),
+                                                   Position::new((13, 17), 3),
+                                                   Position::new((18, 27), 4)]);
+        assert_eq!(search_result.highlight_top_n("<", ">", 3),
+                   "<rust> a <rusty> rust <rustacean>");
+        assert_eq!(search_result.highlight_top_n("<", ">", 0), doc.content());
+        assert_eq!(search_result.highlight_top_n("<", ">", 10),
+                   search_result.highlight("<", ">"));
+    }
+
+    #[test]
+    fn display() {
+        let doc = Document::new(1, "learn to program in rust");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((9, 13), 2),
+                                                   Position::new((20, 24), 4)]);
+        assert_eq!(search_result.to_string(), "learn to [prog]ram in [rust]");
+    }
+
+    #[test]
+    fn sentence_snippet() {
+        let doc = Document::new(1, "Rust is fast. Python is slow!  Rust is safe?\nThe end");
+        let search_result = SearchResult::new(&doc, vec![Position::new((14, 20), 3)]);
+        assert_eq!(search_result.sentence_snippet("<b>", "</b>"), "<b>Python</b> is slow!");
+
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 4), 0),
+                                                   Position::new((8, 12), 2),
+                                                   Position::new((31, 35), 6),
+                                                   Position::new((49, 52), 10)]);
+        assert_eq!(search_result.sentence_snippet("<b>", "</b>"),
+                   "<b>Rust</b> is <b>fast</b>.…<b>Rust</b> is safe?…The <b>end</b>");
+        assert_eq!(SearchResult::new(&doc, vec![]).sentence_snippet("<b>", "</b>"), "");
+    }
+
+    #[test]
+    fn highlight_to() {
+        let doc = Document::new(1, "rust is fun, rust is fast");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 4), 0),
+                                                   Position::new((8, 11), 2),
+                                                   Position::new((13, 17), 3)]);
+        let mut out = vec![];
+        search_result.highlight_to(&mut out, "<b>", "</b>").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), search_result.highlight("<b>", "</b>"));
+    }
+
+    #[test]
+    fn highlight_numbered() {
+        let doc = Document::new(1, "rust & <rustc>");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 4), 0),
+                                                   Position::new((8, 12), 2)]);
+        assert_eq!(search_result.highlight_numbered("mark"),
+                   "<mark data-match=\"0\">rust</mark> &amp; \
+                    &lt;<mark data-match=\"1\">rust</mark>c&gt;");
+    }
+
+    #[test]
+    fn highlight_zero_width() {
+        let doc = Document::new(1, "rust is fun");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 0), 0),
+                                                   Position::new((0, 4), 0),
+                                                   Position::new((5, 5), 1),
+                                                   Position::new((8, 11), 2),
+                                                   Position::new((11, 11), 3)]);
+        assert_eq!(search_result.highlight("<b>", "</b>"), "<b>rust</b> is <b>fun</b>");
+        assert_eq!(search_result.highlight_numbered("mark"),
+                   "<mark data-match=\"0\">rust</mark> is <mark data-match=\"1\">fun</mark>");
+        assert_eq!(search_result.score,
+                   SearchResult::new(&doc,
+                                     vec![Position::new((0, 4), 0), Position::new((8, 11), 2)])
+                       .score);
+    }
+
+    #[test]
+    fn positions_by_word() {
+        let doc = Document::new(1, "rust is rusty");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 2), 0),
+                                                   Position::new((0, 4), 0),
+                                                   Position::new((8, 12), 2),
+                                                   Position::new((8, 13), 2)]);
+        let by_word = search_result.positions_by_word();
+        assert_eq!(by_word.keys().cloned().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(by_word[&0], [Position::new((0, 2), 0), Position::new((0, 4), 0)]);
+        assert_eq!(by_word[&2], [Position::new((8, 12), 2), Position::new((8, 13), 2)]);
+    }
+
+    #[test]
+    fn matched_terms() {
+        let doc = Document::new(1, "rust today, rust tomorrow");
+        let search_result = SearchResult::new(&doc,
+                                              vec![Position::new((0, 2), 0),
+                                                   Position::new((0, 4), 0),
+                                                   Position::new((5, 7), 1),
+                                                   Position::new((5, 10), 1),
+                                                   Position::new((12, 16), 2),
+                                                   Position::new((17, 19), 3)]);
+        assert_eq!(search_result.matched_terms(), ["rust", "today", "to"]);
+    }
+
+    #[test]
+    fn merge_results() {
+        let doc1 = Document::new(1, "rust");
+        let doc2 = Document::new(2, "rust is fun");
+        let doc3 = Document::new(3, "rust and go");
+        let doc2_shard2 = Document::new(2, "rust is fun, and fast");
+        let scored = |doc, score| {
+            let mut result = SearchResult::new(doc, vec![Position::new((0, 4), 0)]);
+            result.score = score;
+            result
+        };
+        let shard1 = vec![scored(&doc1, 3.), scored(&doc2, 1.)];
+        let shard2 = vec![scored(&doc2_shard2, 2.), scored(&doc3, 1.)];
+        let merged = super::merge_results(vec![shard1.clone(), shard2.clone()], 10);
+        let summary: Vec<_> = merged.iter().map(|result| (result.doc.id, result.score)).collect();
+        assert_eq!(summary, [(1, 3.), (2, 2.), (3, 1.)]);
+        assert_eq!(merged[1].doc.content, "rust is fun, and fast");
+        let merged = super::merge_results(vec![shard2, shard1], 2);
+        let summary: Vec<_> = merged.iter().map(|result| (result.doc.id, result.score)).collect();
+        assert_eq!(summary, [(1, 3.), (2, 2.)]);
+    }
+
+    #[test]
+    fn sort_results_nan() {
+        let doc1 = Document::new(1, "rust");
+        let doc2 = Document::new(2, "rust is fun");
+        let doc3 = Document::new(3, "rust and go");
+        let mut results: Vec<_> = [(&doc1, 1.), (&doc2, f32::NAN), (&doc3, 2.)]
+                                      .iter()
+                                      .map(|&(doc, score)| {
+                                          let mut result = SearchResult::new(doc, vec![]);
+                                          result.score = score;
+                                          result
+                                      })
+                                      .collect();
+        super::sort_results(&mut results);
+        let ids: Vec<_> = results.iter().map(|result| result.doc.id).collect();
+        assert_eq!(ids, [2, 3, 1]);
     }
 }
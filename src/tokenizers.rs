@@ -1,6 +1,11 @@
 // Original authorship BurntSushi
 
+use std::ascii::AsciiExt;
+use std::collections::BTreeSet;
 use std::io;
+use std::vec;
+
+use unicode_segmentation::UnicodeSegmentation;
 
 use util::char_utf8::decode_utf8;
 
@@ -10,7 +15,9 @@ pub struct Token {
     /// A single parsed input from the document, possibly after undergoing some series of
     /// transformations.
     pub token: String,
-    /// Information about the position of the token within the document.
+    /// Information about the position of the token within the document. The offsets always refer
+    /// to the span of the original text the token was read from, so filters that transform
+    /// `token` must leave `position` untouched.
     pub position: Position,
 }
 
@@ -23,9 +30,23 @@ impl Token {
         }
     }
 
+    /// Replaces the token's string with the result of applying `f` to it, keeping the token's
+    /// position, so that its offsets still refer to the original text.
+    pub fn transform<F>(&mut self, f: F)
+        where F: FnOnce(&str) -> String
+    {
+        self.token = f(&self.token);
+    }
+
     /// Creates an empty token with capacity reserved for 5 bytes;
     pub fn empty() -> Token {
-        Token::new(String::with_capacity(5), (0, 0), 0)
+        Token::with_capacity(5)
+    }
+
+    /// Creates an empty token with capacity reserved for the given number of bytes, e.g. the
+    /// length of the longest expected word.
+    pub fn with_capacity(capacity: usize) -> Token {
+        Token::new(String::with_capacity(capacity), (0, 0), 0)
     }
 }
 
@@ -49,6 +70,20 @@ impl Position {
             position: position,
         }
     }
+
+    /// Converts the position's byte offsets into `content`, the text the position was read from,
+    /// to char offsets, i.e. the number of chars preceding the beginning and end of the term.
+    /// Note that JavaScript strings are indexed by UTF-16 code units, which differ from chars for
+    /// chars outside the Basic Multilingual Plane.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offsets are out of bounds or don't lie on char boundaries of `content`.
+    pub fn to_char_offsets(&self, content: &str) -> (usize, usize) {
+        let (begin, end) = self.offsets;
+        let char_begin = content[..begin].chars().count();
+        (char_begin, char_begin + content[begin..end].chars().count())
+    }
 }
 
 /// A type that can output a sequence of tokens
@@ -67,6 +102,33 @@ pub trait Tokenizer {
     }
 }
 
+impl<Tknzr: Tokenizer + ?Sized> Tokenizer for Box<Tknzr> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        (**self).read(tok)
+    }
+}
+
+/// Analyzes text into tokens, e.g. to customize how queries are tokenized by
+/// `InvertedIndex::query_with_analyzer`. Any function from text to a tokenizer is an analyzer.
+pub trait Analyzer {
+    /// The tokenizer of analyzed text.
+    type Tokenizer: Tokenizer;
+
+    /// Returns a tokenizer of the given text.
+    fn analyze(&self, text: &str) -> Self::Tokenizer;
+}
+
+impl<F, Tknzr> Analyzer for F
+    where F: Fn(&str) -> Tknzr,
+          Tknzr: Tokenizer
+{
+    type Tokenizer = Tknzr;
+
+    fn analyze(&self, text: &str) -> Tknzr {
+        self(text)
+    }
+}
+
 /// Iterator over a tokenizer's output.
 pub struct Iter<Tknzr> {
     tokenizer: Tknzr,
@@ -93,19 +155,32 @@ impl<Tknzr: Tokenizer> Iterator for Iter<Tknzr> {
 }
 
 /// A tokenizer of english documents encoded in UTF-8.
-pub struct EnglishUtf8<Buf> {
+/// Tokens are separated by whitespace, and consist of the chars accepted by the tokenizer's char
+/// filter, which by default accepts alphanumeric chars; other chars are skipped.
+pub struct EnglishUtf8<Buf, F = fn(char) -> bool> {
     rdr: Buf,
     offset: usize,
     num_tokens: usize,
+    char_filter: F,
 }
 
 impl<Buf: io::BufRead> EnglishUtf8<Buf> {
     /// Creates a new tokenizer backed by the given buffer.
     pub fn new(rdr: Buf) -> EnglishUtf8<Buf> {
+        EnglishUtf8::with_char_filter(rdr, char::is_alphanumeric)
+    }
+}
+
+impl<Buf: io::BufRead, F: FnMut(char) -> bool> EnglishUtf8<Buf, F> {
+    /// Creates a new tokenizer backed by the given buffer, whose tokens consist of the chars
+    /// accepted by `char_filter`, e.g. to keep apostrophes so that "don't" is tokenized as
+    /// "don't" rather than "dont".
+    pub fn with_char_filter(rdr: Buf, char_filter: F) -> EnglishUtf8<Buf, F> {
         EnglishUtf8 {
             rdr: rdr,
             offset: 0,
             num_tokens: 0,
+            char_filter: char_filter,
         }
     }
 }
@@ -117,14 +192,16 @@ impl EnglishUtf8<io::Cursor<Vec<u8>>> {
     {
         EnglishUtf8::new(io::Cursor::new(bytes.into()))
     }
+}
 
+impl<F> EnglishUtf8<io::Cursor<Vec<u8>>, F> {
     /// Reset the backing buffer to position 0.
     pub fn reset(&mut self) {
         self.rdr.set_position(0);
     }
 }
 
-impl<Buf: io::BufRead> Tokenizer for EnglishUtf8<Buf> {
+impl<Buf: io::BufRead, F: FnMut(char) -> bool> Tokenizer for EnglishUtf8<Buf, F> {
     fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
         let mut consumed = 0;
         tok.token.clear();
@@ -160,7 +237,7 @@ impl<Buf: io::BufRead> Tokenizer for EnglishUtf8<Buf> {
                         break 'LOOP;
                     }
                 }
-                if !c.is_alphanumeric() {
+                if !(self.char_filter)(c) {
                     self.offset += n;
                     continue;
                 }
@@ -179,13 +256,162 @@ impl<Buf: io::BufRead> Tokenizer for EnglishUtf8<Buf> {
     }
 }
 
+/// A tokenizer of UTF-8 text that splits it into words at the word boundaries of Unicode Standard
+/// Annex #29, rather than on whitespace and non-alphanumeric chars like `EnglishUtf8`. This
+/// segments text correctly across scripts: e.g. combining marks stay part of their word, each
+/// ideograph of Chinese or Japanese text is its own word, and "can't" and "3.14" are single words.
+/// Segments without any alphanumeric chars, e.g. whitespace and punctuation, are skipped.
+pub struct UnicodeWordsUtf8 {
+    text: String,
+    words: vec::IntoIter<(usize, usize)>,
+    num_tokens: usize,
+}
+
+impl UnicodeWordsUtf8 {
+    /// Creates a new tokenizer of the given text.
+    pub fn new<S: Into<String>>(text: S) -> UnicodeWordsUtf8 {
+        let text = text.into();
+        let words: Vec<_> = text.split_word_bound_indices()
+                                .filter(|&(_, word)| word.chars().any(char::is_alphanumeric))
+                                .map(|(begin, word)| (begin, begin + word.len()))
+                                .collect();
+        UnicodeWordsUtf8 {
+            text: text,
+            words: words.into_iter(),
+            num_tokens: 0,
+        }
+    }
+}
+
+impl Tokenizer for UnicodeWordsUtf8 {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        let (begin, end) = match self.words.next() {
+            Some(word) => word,
+            None => return Ok(false),
+        };
+        tok.token.clear();
+        tok.token.push_str(&self.text[begin..end]);
+        tok.position = Position::new((begin, end), self.num_tokens);
+        self.num_tokens += 1;
+        Ok(true)
+    }
+}
+
+/// A tokenizer of UTF-8 documents that splits its input on a delimiter char rather than on
+/// whitespace, e.g. for comma-separated tag fields. Each field value becomes one token, with
+/// surrounding whitespace trimmed; empty fields are skipped.
+pub struct DelimiterUtf8<Buf> {
+    rdr: Buf,
+    delim: char,
+    offset: usize,
+    num_tokens: usize,
+}
+
+impl<Buf: io::BufRead> DelimiterUtf8<Buf> {
+    /// Creates a new tokenizer backed by the given buffer, splitting on `delim`.
+    pub fn new(rdr: Buf, delim: char) -> DelimiterUtf8<Buf> {
+        DelimiterUtf8 {
+            rdr: rdr,
+            delim: delim,
+            offset: 0,
+            num_tokens: 0,
+        }
+    }
+}
+
+impl DelimiterUtf8<io::Cursor<Vec<u8>>> {
+    /// Construct a DelimiterUtf8 tokenizer backed by a byte buffer, splitting on `delim`.
+    pub fn from_bytes<B>(bytes: B, delim: char) -> DelimiterUtf8<io::Cursor<Vec<u8>>>
+        where B: Into<Vec<u8>>
+    {
+        DelimiterUtf8::new(io::Cursor::new(bytes.into()), delim)
+    }
+}
+
+impl<Buf: io::BufRead> Tokenizer for DelimiterUtf8<Buf> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        let mut consumed = 0;
+        // The length of the token without trailing whitespace
+        let mut trimmed_len = 0;
+        tok.token.clear();
+        'LOOP: loop {
+            self.rdr.consume(consumed);
+            consumed = 0;
+            let buf = try!(self.rdr.fill_buf());
+            if buf.is_empty() {
+                if tok.token.is_empty() {
+                    return Ok(false);
+                } else {
+                    break 'LOOP;
+                }
+            }
+            while consumed < buf.len() {
+                let bytes = &buf[consumed..];
+                let (n, c) = match decode_utf8(bytes) {
+                    None => {
+                        consumed += 1;
+                        self.offset += 1;
+                        continue;
+                    }
+                    Some((n, c)) => {
+                        consumed += n;
+                        (n, c)
+                    }
+                };
+                self.offset += n;
+                if c == self.delim {
+                    if tok.token.is_empty() {
+                        continue;
+                    } else {
+                        break 'LOOP;
+                    }
+                }
+                if tok.token.is_empty() {
+                    if c.is_whitespace() {
+                        continue;
+                    }
+                    tok.position.offsets.0 = self.offset - n;
+                }
+                tok.token.push(c);
+                if !c.is_whitespace() {
+                    trimmed_len = tok.token.len();
+                    tok.position.offsets.1 = self.offset;
+                }
+            }
+        }
+        self.rdr.consume(consumed);
+        tok.token.truncate(trimmed_len);
+        tok.position.position = self.num_tokens;
+        self.num_tokens += 1;
+        Ok(true)
+    }
+}
+
 /// An analyzer that tokenizes its input and returns each subslice of each token that starts from
-/// the first char.
+/// the first char. The last of them, i.e. the whole token, keeps the token's offsets, so that it
+/// ends at the end of the word it was read from.
 pub struct NgramsFilter<Tknzr: Tokenizer> {
     tokenizer: Tknzr,
     next: Vec<Token>,
 }
 
+impl<Tknzr: Tokenizer> NgramsFilter<Tknzr> {
+    /// Creates a new NgramsFilter with the specified backing tokenizer.
+    pub fn after_tokenizer(tokenizer: Tknzr) -> NgramsFilter<Tknzr> {
+        NgramsFilter::with_capacity(tokenizer, 0)
+    }
+
+    /// Creates a new NgramsFilter with the specified backing tokenizer, reserving room for the
+    /// ngrams of words of up to `max_word_len` chars, so that tokenizing such words doesn't
+    /// reallocate the filter's buffer.
+    pub fn with_capacity(tokenizer: Tknzr, max_word_len: usize) -> NgramsFilter<Tknzr> {
+        NgramsFilter {
+            tokenizer: tokenizer,
+            next: Vec::with_capacity(max_word_len),
+        }
+    }
+}
+
 impl<Buf: io::BufRead> NgramsFilter<EnglishUtf8<Buf>> {
     /// Creates a new NgramsFilter with a backing English UTF-8 tokenizer backed by the buffer.
     pub fn new(buf: Buf) -> NgramsFilter<EnglishUtf8<Buf>> {
@@ -220,19 +446,84 @@ impl<Tknzr: Tokenizer> Tokenizer for NgramsFilter<Tknzr> {
                 match self.tokenizer.read(tok) {
                     done @ Ok(false) | done @ Err(_) => done,
                     Ok(true) => {
-                        let start = tok.position.offsets.0;
-                        let chars: Vec<_> = tok.token
-                                               .char_indices()
-                                               .map(|(offset, c)| (start + offset, c))
-                                               .collect();
-                        self.next.extend((1..chars.len() + 1).rev().map(|to| {
-                            let token: String = chars[..to]
-                                                    .iter()
-                                                    .map(|&(_, c)| c)
-                                                    .collect();
-                            let (last_idx, last_char) = chars[to - 1];
-                            let finish = last_idx + last_char.len_utf8();
-                            Token::new(token, (start, finish), tok.position.position)
+                        let (start, end) = tok.position.offsets;
+                        let position = tok.position.position;
+                        // Each ngram is sliced from the token, so its string is allocated once, at
+                        // its exact length.
+                        {
+                            let token = &tok.token;
+                            self.next.extend(token.char_indices().rev().map(|(offset, c)| {
+                                let end = offset + c.len_utf8();
+                                Token::new(&token[..end], (start, start + end), position)
+                            }));
+                        }
+                        // The whole token ends where the tokenizer says it does, even if chars
+                        // within it were skipped, as in "don't"
+                        if let Some(whole) = self.next.first_mut() {
+                            whole.position.offsets.1 = end;
+                        }
+                        *tok = self.next.pop().unwrap();
+                        Ok(true)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An analyzer that tokenizes its input and returns each subslice of each token that ends at the
+/// last char, e.g. for suffix search. "program" produces "m", "am", "ram", and so on.
+pub struct SuffixNgramsFilter<Tknzr: Tokenizer> {
+    tokenizer: Tknzr,
+    next: Vec<Token>,
+}
+
+impl<Tknzr: Tokenizer> SuffixNgramsFilter<Tknzr> {
+    /// Creates a new SuffixNgramsFilter with the specified backing tokenizer.
+    pub fn after_tokenizer(tokenizer: Tknzr) -> SuffixNgramsFilter<Tknzr> {
+        SuffixNgramsFilter {
+            tokenizer: tokenizer,
+            next: vec![],
+        }
+    }
+}
+
+impl<Buf: io::BufRead> SuffixNgramsFilter<EnglishUtf8<Buf>> {
+    /// Creates a new SuffixNgramsFilter with a backing English UTF-8 tokenizer backed by the
+    /// buffer.
+    pub fn new(buf: Buf) -> SuffixNgramsFilter<EnglishUtf8<Buf>> {
+        SuffixNgramsFilter::after_tokenizer(EnglishUtf8::new(buf))
+    }
+}
+
+impl SuffixNgramsFilter<EnglishUtf8<io::Cursor<Vec<u8>>>> {
+    /// Creates a new SuffixNgramsFilter with a backing English UTF-8 tokenizer backed by the
+    /// bytes.
+    pub fn from_bytes<B>(bytes: B) -> SuffixNgramsFilter<EnglishUtf8<io::Cursor<Vec<u8>>>>
+        where B: Into<Vec<u8>>
+    {
+        SuffixNgramsFilter::after_tokenizer(EnglishUtf8::from_bytes(bytes))
+    }
+}
+
+impl<Tknzr: Tokenizer> Tokenizer for SuffixNgramsFilter<Tknzr> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        match self.next.pop() {
+            Some(next) => {
+                *tok = next;
+                Ok(true)
+            }
+            None => {
+                match self.tokenizer.read(tok) {
+                    done @ Ok(false) | done @ Err(_) => done,
+                    Ok(true) => {
+                        // Offsets are computed back from the end of the token.
+                        let finish = tok.position.offsets.1;
+                        let len = tok.token.len();
+                        self.next.extend(tok.token.char_indices().map(|(from, _)| {
+                            Token::new(&tok.token[from..],
+                                       (finish - (len - from), finish),
+                                       tok.position.position)
                         }));
                         *tok = self.next.pop().unwrap();
                         Ok(true)
@@ -243,15 +534,68 @@ impl<Tknzr: Tokenizer> Tokenizer for NgramsFilter<Tknzr> {
     }
 }
 
+/// The locale whose casing rules are used when lowercasing tokens.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, RustcDecodable, RustcEncodable)]
+pub enum Locale {
+    /// Locale-independent Unicode lowercasing.
+    Default,
+    /// Turkish lowercasing, which maps 'I' to dotless 'ı' and 'İ' to 'i'.
+    Turkish,
+    /// ASCII-only lowercasing, which is cheaper than Unicode lowercasing but leaves non-ASCII
+    /// chars untouched, so e.g. 'Ä' isn't folded to 'ä'. Suitable for mostly-ASCII text.
+    Ascii,
+}
+
+impl Default for Locale {
+    fn default() -> Locale {
+        Locale::Default
+    }
+}
+
+impl Locale {
+    /// Lowercases the string according to the locale's casing rules.
+    pub fn to_lowercase(self, s: &str) -> String {
+        match self {
+            Locale::Default => s.to_lowercase(),
+            Locale::Turkish => {
+                let mut lowercase = String::with_capacity(s.len());
+                for c in s.chars() {
+                    match c {
+                        'I' => lowercase.push('ı'),
+                        'İ' => lowercase.push('i'),
+                        c => lowercase.extend(c.to_lowercase()),
+                    }
+                }
+                lowercase
+            }
+            Locale::Ascii => {
+                let mut lowercase = s.to_owned();
+                lowercase.make_ascii_lowercase();
+                lowercase
+            }
+        }
+    }
+}
+
 /// An analyzer that tokenizes and lowercases its input
 pub struct LowercaseFilter<Tknzr: Tokenizer> {
     tokenizer: Tknzr,
+    locale: Locale,
 }
 
 impl<Tknzr: Tokenizer> LowercaseFilter<Tknzr> {
     /// Creates a new LowercaseFilter with the specified backing tokenizer.
     pub fn after_tokenizer(tokenizer: Tknzr) -> LowercaseFilter<Tknzr> {
-        LowercaseFilter { tokenizer: tokenizer }
+        LowercaseFilter::with_locale(tokenizer, Locale::Default)
+    }
+
+    /// Creates a new LowercaseFilter with the specified backing tokenizer that lowercases
+    /// according to the casing rules of `locale`.
+    pub fn with_locale(tokenizer: Tknzr, locale: Locale) -> LowercaseFilter<Tknzr> {
+        LowercaseFilter {
+            tokenizer: tokenizer,
+            locale: locale,
+        }
     }
 }
 
@@ -277,13 +621,303 @@ impl<Tknzr: Tokenizer> Tokenizer for LowercaseFilter<Tknzr> {
         match self.tokenizer.read(tok) {
             done @ Ok(false) | done @ Err(_) => done,
             done @ Ok(true) => {
-                tok.token = tok.token.to_lowercase();
+                match self.locale {
+                    // Lowercases in place, avoiding an allocation per token
+                    Locale::Ascii => tok.token.make_ascii_lowercase(),
+                    locale => tok.transform(|token| locale.to_lowercase(token)),
+                }
                 done
             }
         }
     }
 }
 
+/// An analyzer that transforms each token's string with a function, e.g. for stemming or
+/// normalization. The tokens' offsets are left unchanged, so they still refer to the original
+/// text.
+pub struct TransformFilter<Tknzr: Tokenizer, F> {
+    tokenizer: Tknzr,
+    transform: F,
+}
+
+impl<Tknzr: Tokenizer, F: FnMut(&str) -> String> TransformFilter<Tknzr, F> {
+    /// Creates a new TransformFilter applying `transform` to the output of the tokenizer.
+    pub fn after_tokenizer(tokenizer: Tknzr, transform: F) -> TransformFilter<Tknzr, F> {
+        TransformFilter {
+            tokenizer: tokenizer,
+            transform: transform,
+        }
+    }
+}
+
+impl<Tknzr: Tokenizer, F: FnMut(&str) -> String> Tokenizer for TransformFilter<Tknzr, F> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        match self.tokenizer.read(tok) {
+            done @ Ok(false) | done @ Err(_) => done,
+            done @ Ok(true) => {
+                tok.transform(&mut self.transform);
+                done
+            }
+        }
+    }
+}
+
+/// An analyzer that limits the length of the tokens it outputs, either by truncating or by
+/// dropping tokens longer than a maximum number of chars. Useful to keep very long "words", such
+/// as URLs or encoded blobs, from exploding the number of ngrams.
+pub struct MaxLengthFilter<Tknzr: Tokenizer> {
+    tokenizer: Tknzr,
+    max_chars: usize,
+    truncate: bool,
+}
+
+impl<Tknzr: Tokenizer> MaxLengthFilter<Tknzr> {
+    /// Creates a new MaxLengthFilter that truncates tokens to `max_chars` chars. A truncated
+    /// token keeps its start offset, and its end offset is moved to the end of the truncation.
+    pub fn truncating(tokenizer: Tknzr, max_chars: usize) -> MaxLengthFilter<Tknzr> {
+        MaxLengthFilter {
+            tokenizer: tokenizer,
+            max_chars: max_chars,
+            truncate: true,
+        }
+    }
+
+    /// Creates a new MaxLengthFilter that drops tokens longer than `max_chars` chars.
+    pub fn dropping(tokenizer: Tknzr, max_chars: usize) -> MaxLengthFilter<Tknzr> {
+        MaxLengthFilter {
+            tokenizer: tokenizer,
+            max_chars: max_chars,
+            truncate: false,
+        }
+    }
+}
+
+impl<Tknzr: Tokenizer> Tokenizer for MaxLengthFilter<Tknzr> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        loop {
+            match self.tokenizer.read(tok) {
+                done @ Ok(false) | done @ Err(_) => return done,
+                done @ Ok(true) => {
+                    let truncate_at = match tok.token.char_indices().nth(self.max_chars) {
+                        None => return done,
+                        Some((idx, _)) => idx,
+                    };
+                    if self.truncate {
+                        tok.token.truncate(truncate_at);
+                        tok.position.offsets.1 = tok.position.offsets.0 + truncate_at;
+                        return done;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An analyzer that drops tokens found in a set of stop words. The remaining tokens keep their
+/// positions, so dropped tokens leave gaps in the positions.
+pub struct StopWordsFilter<Tknzr: Tokenizer> {
+    tokenizer: Tknzr,
+    stop_words: BTreeSet<String>,
+    // The locale whose casing rules tokens are lowercased by before they're looked up, if any
+    ignore_case: Option<Locale>,
+}
+
+impl<Tknzr: Tokenizer> StopWordsFilter<Tknzr> {
+    /// Creates a new StopWordsFilter dropping the given stop words from the output of the
+    /// tokenizer.
+    pub fn after_tokenizer(tokenizer: Tknzr,
+                           stop_words: BTreeSet<String>)
+                           -> StopWordsFilter<Tknzr> {
+        StopWordsFilter {
+            tokenizer: tokenizer,
+            stop_words: stop_words,
+            ignore_case: None,
+        }
+    }
+
+    /// Creates a new StopWordsFilter dropping the tokens of the tokenizer whose lowercase form,
+    /// according to the casing rules of `locale`, is one of the given lowercase stop words, e.g.
+    /// before tokens are lowercased.
+    pub fn ignoring_case(tokenizer: Tknzr,
+                         stop_words: BTreeSet<String>,
+                         locale: Locale)
+                         -> StopWordsFilter<Tknzr> {
+        StopWordsFilter {
+            ignore_case: Some(locale),
+            ..StopWordsFilter::after_tokenizer(tokenizer, stop_words)
+        }
+    }
+
+    fn is_stop_word(&self, token: &str) -> bool {
+        if self.stop_words.is_empty() {
+            return false;
+        }
+        match self.ignore_case {
+            Some(locale) => self.stop_words.contains(&locale.to_lowercase(token)),
+            None => self.stop_words.contains(token),
+        }
+    }
+}
+
+impl<Tknzr: Tokenizer> Tokenizer for StopWordsFilter<Tknzr> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        loop {
+            match self.tokenizer.read(tok) {
+                done @ Ok(false) | done @ Err(_) => return done,
+                done @ Ok(true) => {
+                    if !self.is_stop_word(&tok.token) {
+                        return done;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An analyzer that, after each token made up of several words joined in camelCase or snake_case,
+/// additionally outputs each of the words, e.g. "getUserName" produces "getUserName", "get",
+/// "User" and "Name", so that identifiers can be searched by their words. The words have the
+/// same position as their token, and offsets spanning just the word.
+///
+/// Offsets are computed from the token's string, so it must not have been transformed. Note that
+/// `EnglishUtf8` skips underscores by default; tokenize with `EnglishUtf8::with_char_filter` to
+/// keep them for splitting snake_case.
+pub struct WordSplitFilter<Tknzr: Tokenizer> {
+    tokenizer: Tknzr,
+    next: Vec<Token>,
+}
+
+impl<Tknzr: Tokenizer> WordSplitFilter<Tknzr> {
+    /// Creates a new WordSplitFilter splitting the output of the tokenizer.
+    pub fn after_tokenizer(tokenizer: Tknzr) -> WordSplitFilter<Tknzr> {
+        WordSplitFilter {
+            tokenizer: tokenizer,
+            next: vec![],
+        }
+    }
+}
+
+impl<Tknzr: Tokenizer> Tokenizer for WordSplitFilter<Tknzr> {
+    fn read(&mut self, tok: &mut Token) -> io::Result<bool> {
+        if let Some(next) = self.next.pop() {
+            *tok = next;
+            return Ok(true);
+        }
+        match self.tokenizer.read(tok) {
+            done @ Ok(false) | done @ Err(_) => done,
+            done @ Ok(true) => {
+                let words = split_words(&tok.token);
+                // A token without separators is a single word, which was already output.
+                if words != [(0, tok.token.len())] {
+                    let start = tok.position.offsets.0;
+                    let position = tok.position.position;
+                    self.next.extend(words.into_iter().rev().map(|(begin, end)| {
+                        Token::new(&tok.token[begin..end], (start + begin, start + end), position)
+                    }));
+                }
+                done
+            }
+        }
+    }
+}
+
+/// Returns the byte ranges of the words of a token, separated by underscores or by camelCase
+/// boundaries, i.e. before an uppercase char following a lowercase char or digit, or before the
+/// last uppercase char of a run of them followed by a lowercase char, as in "HTTPServer".
+fn split_words(token: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<_> = token.char_indices().collect();
+    let mut words = vec![];
+    let mut word_start = None;
+    for (i, &(idx, c)) in chars.iter().enumerate() {
+        if c == '_' {
+            if let Some(begin) = word_start.take() {
+                words.push((begin, idx));
+            }
+            continue;
+        }
+        match word_start {
+            None => word_start = Some(idx),
+            Some(begin) => {
+                let prev = chars[i - 1].1;
+                let next_is_lowercase = chars.get(i + 1).map_or(false, |&(_, c)| c.is_lowercase());
+                if c.is_uppercase() &&
+                   (prev.is_lowercase() || prev.is_numeric() ||
+                    prev.is_uppercase() && next_is_lowercase) {
+                    words.push((begin, idx));
+                    word_start = Some(idx);
+                }
+            }
+        }
+    }
+    if let Some(begin) = word_start {
+        words.push((begin, token.len()));
+    }
+    words
+}
+
+/// A builder for chaining filters after a tokenizer, e.g.
+/// `TokenizerChain::from_bytes(bytes).ngrams().lowercase().build()`.
+/// Filters are applied in the order they are added.
+pub struct TokenizerChain {
+    tokenizer: Box<Tokenizer>,
+}
+
+impl TokenizerChain {
+    /// Starts a chain with the given tokenizer.
+    pub fn new<Tknzr: Tokenizer + 'static>(tokenizer: Tknzr) -> TokenizerChain {
+        TokenizerChain { tokenizer: Box::new(tokenizer) }
+    }
+
+    /// Starts a chain with an English UTF-8 tokenizer backed by the bytes.
+    pub fn from_bytes<B>(bytes: B) -> TokenizerChain
+        where B: Into<Vec<u8>>
+    {
+        TokenizerChain::new(EnglishUtf8::from_bytes(bytes))
+    }
+
+    /// Adds an `NgramsFilter` to the chain.
+    pub fn ngrams(self) -> TokenizerChain {
+        TokenizerChain::new(NgramsFilter::after_tokenizer(self.tokenizer))
+    }
+
+    /// Adds a `WordSplitFilter` to the chain.
+    pub fn word_split(self) -> TokenizerChain {
+        TokenizerChain::new(WordSplitFilter::after_tokenizer(self.tokenizer))
+    }
+
+    /// Adds a `LowercaseFilter` to the chain.
+    pub fn lowercase(self) -> TokenizerChain {
+        TokenizerChain::new(LowercaseFilter::after_tokenizer(self.tokenizer))
+    }
+
+    /// Adds a `LowercaseFilter` using the casing rules of `locale` to the chain.
+    pub fn lowercase_in(self, locale: Locale) -> TokenizerChain {
+        TokenizerChain::new(LowercaseFilter::with_locale(self.tokenizer, locale))
+    }
+
+    /// Adds a `StopWordsFilter` dropping the given stop words to the chain.
+    pub fn stopwords(self, stop_words: BTreeSet<String>) -> TokenizerChain {
+        TokenizerChain::new(StopWordsFilter::after_tokenizer(self.tokenizer, stop_words))
+    }
+
+    /// Adds a truncating `MaxLengthFilter` to the chain.
+    pub fn max_length(self, max_chars: usize) -> TokenizerChain {
+        TokenizerChain::new(MaxLengthFilter::truncating(self.tokenizer, max_chars))
+    }
+
+    /// Adds a `TransformFilter` applying `transform` to the chain.
+    pub fn transform<F>(self, transform: F) -> TokenizerChain
+        where F: FnMut(&str) -> String + 'static
+    {
+        TokenizerChain::new(TransformFilter::after_tokenizer(self.tokenizer, transform))
+    }
+
+    /// Returns the chained tokenizer.
+    pub fn build(self) -> Box<Tokenizer> {
+        self.tokenizer
+    }
+}
+
 /// Creates a lowercase-ngrams tokenizer by chaining two filters.
 pub fn lowercase_ngrams<B>(bytes: B)
                            -> LowercaseFilter<NgramsFilter<EnglishUtf8<io::Cursor<Vec<u8>>>>>
@@ -292,15 +926,45 @@ pub fn lowercase_ngrams<B>(bytes: B)
     LowercaseFilter::after_tokenizer(NgramsFilter::from_bytes(bytes))
 }
 
+/// Tokenizes the string into the terms, and their positions, that an `InvertedIndex` with the
+/// default settings indexes it under: the lowercased ngrams of its words. This is useful to
+/// preview or debug indexing without constructing an index.
+pub fn tokenize(s: &str) -> Vec<(String, Position)> {
+    lowercase_ngrams(s)
+        .into_iter()
+        .map(Result::unwrap)
+        .map(|Token { token, position }| (token, position))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeSet;
     use std::io;
-    use super::{Tokenizer, Token, EnglishUtf8};
+    use super::{Tokenizer, Token, DelimiterUtf8, EnglishUtf8, Locale, LowercaseFilter,
+                MaxLengthFilter, NgramsFilter, Position, StopWordsFilter, SuffixNgramsFilter,
+                TokenizerChain, TransformFilter, UnicodeWordsUtf8, WordSplitFilter,
+                lowercase_ngrams};
 
     fn collect<T: Tokenizer>(tokenizer: T) -> Vec<Token> {
         tokenizer.into_iter().collect::<Result<Vec<_>, _>>().unwrap()
     }
 
+    /// Asserts that each token output by `tokenizer` spans a prefix of the untransformed token
+    /// read from `text` at the same position.
+    fn assert_offsets_preserved<T: Tokenizer>(text: &str, tokenizer: T) {
+        let original = collect(EnglishUtf8::from_bytes(text));
+        for Token { position, .. } in collect(tokenizer) {
+            let (begin, end) = position.offsets;
+            let (original_begin, original_end) = original[position.position].position.offsets;
+            assert_eq!(begin, original_begin);
+            assert!(end <= original_end);
+            assert!(original[position.position]
+                        .token
+                        .starts_with(&text[begin..end]));
+        }
+    }
+
     #[test]
     fn tiny_buffer() {
         let bytes = &b"Hi, Dave! How are you?"[..];
@@ -313,4 +977,242 @@ mod tests {
                         Token::new("are", (14, 17), 3),
                         Token::new("you", (18, 21), 4)]);
     }
+
+    #[test]
+    fn max_length_truncating() {
+        let long_word: String = (0..1000).map(|_| 'a').collect();
+        let text = format!("hi {} there", long_word);
+        let toks = collect(MaxLengthFilter::truncating(EnglishUtf8::from_bytes(&text[..]), 10));
+        assert_eq!(toks,
+                   vec![Token::new("hi", (0, 2), 0),
+                        Token::new(&long_word[..10], (3, 13), 1),
+                        Token::new("there", (1004, 1009), 2)]);
+        let ngrams = collect(NgramsFilter::after_tokenizer(
+            MaxLengthFilter::truncating(EnglishUtf8::from_bytes(&long_word[..]), 10)));
+        assert_eq!(ngrams.len(), 10);
+    }
+
+    #[test]
+    fn max_length_dropping() {
+        let text = "hi supercalifragilistic there";
+        let toks = collect(MaxLengthFilter::dropping(EnglishUtf8::from_bytes(text), 10));
+        assert_eq!(toks,
+                   vec![Token::new("hi", (0, 2), 0), Token::new("there", (24, 29), 2)]);
+    }
+
+    #[test]
+    fn transform_preserves_offsets() {
+        let text = "Hello, W\u{f6}rld! \u{130}stanbul";
+        let reversed = |s: &str| s.chars().rev().collect::<String>();
+        let toks = collect(TransformFilter::after_tokenizer(
+            LowercaseFilter::from_bytes(text), reversed));
+        assert_eq!(toks,
+                   vec![Token::new("olleh", (0, 5), 0),
+                        Token::new("dlr\u{f6}w", (7, 13), 1),
+                        Token::new("lubnats\u{307}i", (15, 24), 2)]);
+        assert_offsets_preserved(text,
+                                 TransformFilter::after_tokenizer(
+                                     LowercaseFilter::from_bytes(text), reversed));
+        assert_offsets_preserved(text,
+                                 TransformFilter::after_tokenizer(
+                                     LowercaseFilter::after_tokenizer(
+                                         NgramsFilter::from_bytes(text)), reversed));
+    }
+
+    #[test]
+    fn tokenizer_chain() {
+        let text = "The quick brown fox, the end";
+        assert_eq!(collect(TokenizerChain::from_bytes(text).ngrams().lowercase().build()),
+                   collect(lowercase_ngrams(text)));
+        let stop_words: BTreeSet<String> = ["the".into()].iter().cloned().collect();
+        let chain = TokenizerChain::from_bytes(text)
+                        .lowercase()
+                        .stopwords(stop_words.clone())
+                        .ngrams()
+                        .build();
+        let nested = NgramsFilter::after_tokenizer(
+            StopWordsFilter::after_tokenizer(LowercaseFilter::from_bytes(text), stop_words));
+        let toks = collect(chain);
+        assert_eq!(toks, collect(nested));
+        assert_eq!(toks[0], Token::new("q", (4, 5), 1));
+        assert!(toks.iter().all(|tok| tok.token != "the"));
+    }
+
+    #[test]
+    fn stop_words_ignoring_case() {
+        let stop_words: BTreeSet<_> = ["ile".to_string()].iter().cloned().collect();
+        let toks = collect(StopWordsFilter::ignoring_case(EnglishUtf8::from_bytes("İLE Ankara"),
+                                                          stop_words.clone(),
+                                                          Locale::Turkish));
+        assert_eq!(toks, vec![Token::new("Ankara", (5, 11), 1)]);
+        let toks = collect(StopWordsFilter::ignoring_case(EnglishUtf8::from_bytes("ILE"),
+                                                          stop_words,
+                                                          Locale::Turkish));
+        assert_eq!(toks.len(), 1);
+    }
+
+    #[test]
+    fn delimiter() {
+        assert_eq!(collect(DelimiterUtf8::from_bytes("a,b,c", ',')),
+                   vec![Token::new("a", (0, 1), 0),
+                        Token::new("b", (2, 3), 1),
+                        Token::new("c", (4, 5), 2)]);
+        let bytes = &b"rust, web dev ,,x"[..];
+        let buf = io::BufReader::with_capacity(1, bytes);
+        assert_eq!(collect(DelimiterUtf8::new(buf, ',')),
+                   vec![Token::new("rust", (0, 4), 0),
+                        Token::new("web dev", (6, 13), 1),
+                        Token::new("x", (16, 17), 2)]);
+    }
+
+    #[test]
+    fn unicode_words() {
+        let text = "Hello, \u{4e16}\u{754c}! cafe\u{301} can't cost 3.14";
+        assert_eq!(collect(UnicodeWordsUtf8::new(text)),
+                   vec![Token::new("Hello", (0, 5), 0),
+                        Token::new("\u{4e16}", (7, 10), 1),
+                        Token::new("\u{754c}", (10, 13), 2),
+                        Token::new("cafe\u{301}", (15, 21), 3),
+                        Token::new("can't", (22, 27), 4),
+                        Token::new("cost", (28, 32), 5),
+                        Token::new("3.14", (33, 37), 6)]);
+        assert!(collect(UnicodeWordsUtf8::new(" ... ")).is_empty());
+    }
+
+    #[test]
+    fn suffix_ngrams() {
+        let toks = collect(SuffixNgramsFilter::from_bytes("an \u{e9}t\u{e9}"));
+        assert_eq!(toks,
+                   vec![Token::new("n", (1, 2), 0),
+                        Token::new("an", (0, 2), 0),
+                        Token::new("\u{e9}", (6, 8), 1),
+                        Token::new("t\u{e9}", (5, 8), 1),
+                        Token::new("\u{e9}t\u{e9}", (3, 8), 1)]);
+        let suffixes = collect(SuffixNgramsFilter::from_bytes("navigation"));
+        assert!(suffixes.contains(&Token::new("tion", (6, 10), 0)));
+        let prefixes = collect(NgramsFilter::from_bytes("navigation"));
+        assert!(prefixes.iter().all(|tok| tok.token != "tion"));
+    }
+
+    #[test]
+    fn turkish_lowercase() {
+        let text = "İstanbul IRMAK Ilık";
+        let tokens = collect(LowercaseFilter::with_locale(EnglishUtf8::from_bytes(text),
+                                                          Locale::Turkish));
+        let tokens: Vec<_> = tokens.into_iter().map(|tok| tok.token).collect();
+        assert_eq!(tokens, vec!["istanbul", "ırmak", "ılık"]);
+        assert_offsets_preserved(text,
+                                 LowercaseFilter::with_locale(EnglishUtf8::from_bytes(text),
+                                                              Locale::Turkish));
+        assert_eq!(Locale::Default.to_lowercase("IRMAK"), "irmak");
+    }
+
+    #[test]
+    fn char_offsets() {
+        let text = "héllo wörld, 日本語 ok";
+        let tokens = collect(EnglishUtf8::from_bytes(text));
+        let offsets: Vec<_> = tokens.iter().map(|tok| tok.position.offsets).collect();
+        assert_eq!(offsets, vec![(0, 6), (7, 13), (15, 24), (25, 27)]);
+        let char_offsets: Vec<_> = tokens.iter()
+                                         .map(|tok| tok.position.to_char_offsets(text))
+                                         .collect();
+        assert_eq!(char_offsets, vec![(0, 5), (6, 11), (13, 16), (17, 19)]);
+        let chars: Vec<_> = text.chars().collect();
+        let words: Vec<String> = char_offsets.iter()
+                                             .map(|&(begin, end)| {
+                                                 chars[begin..end].iter().cloned().collect()
+                                             })
+                                             .collect();
+        assert_eq!(words, vec!["héllo", "wörld", "日本語", "ok"]);
+        assert_eq!(Position::new((0, 0), 0).to_char_offsets(""), (0, 0));
+    }
+
+    #[test]
+    fn ngrams() {
+        let toks = collect(NgramsFilter::from_bytes("Hi, Dävé!"));
+        assert_eq!(toks,
+                   vec![Token::new("H", (0, 1), 0),
+                        Token::new("Hi", (0, 2), 0),
+                        Token::new("D", (4, 5), 1),
+                        Token::new("Dä", (4, 7), 1),
+                        Token::new("Däv", (4, 8), 1),
+                        Token::new("Dävé", (4, 10), 1)]);
+    }
+
+    #[test]
+    fn ngrams_skipped_chars() {
+        let toks = collect(NgramsFilter::from_bytes("don't"));
+        assert_eq!(toks,
+                   vec![Token::new("d", (0, 1), 0),
+                        Token::new("do", (0, 2), 0),
+                        Token::new("don", (0, 3), 0),
+                        Token::new("dont", (0, 5), 0)]);
+    }
+
+    #[test]
+    fn char_filter() {
+        let text = "Don't stop, it's 'fine'";
+        let tokens: Vec<_> = collect(EnglishUtf8::from_bytes(text))
+                                 .into_iter()
+                                 .map(|tok| tok.token)
+                                 .collect();
+        assert_eq!(tokens, vec!["Dont", "stop", "its", "fine"]);
+        let tokenizer = EnglishUtf8::with_char_filter(io::Cursor::new(text),
+                                                      |c: char| c.is_alphanumeric() || c == '\'');
+        assert_eq!(collect(tokenizer),
+                   vec![Token::new("Don't", (0, 5), 0),
+                        Token::new("stop", (6, 10), 1),
+                        Token::new("it's", (12, 16), 2),
+                        Token::new("'fine'", (17, 23), 3)]);
+    }
+
+    #[test]
+    fn ngrams_with_capacity() {
+        let text = "Antidisestablishmentarianism and pneumonoultramicroscopicsilicovolcanoconiosis";
+        let expected = collect(NgramsFilter::from_bytes(text));
+        let tokenizer = NgramsFilter::with_capacity(EnglishUtf8::from_bytes(text), 45);
+        assert_eq!(collect(tokenizer), expected);
+        assert_eq!(expected.len(), 28 + 3 + 45);
+        assert_eq!(expected[27], Token::new("Antidisestablishmentarianism", (0, 28), 0));
+        assert!(Token::with_capacity(45).token.capacity() >= 45);
+    }
+
+    #[test]
+    fn word_split() {
+        let text = "getUserName HTTPServer snake_case_id plain";
+        let keep_underscores = |c: char| c.is_alphanumeric() || c == '_';
+        let tokenizer = EnglishUtf8::with_char_filter(io::Cursor::new(text), keep_underscores);
+        assert_eq!(collect(WordSplitFilter::after_tokenizer(tokenizer)),
+                   vec![Token::new("getUserName", (0, 11), 0),
+                        Token::new("get", (0, 3), 0),
+                        Token::new("User", (3, 7), 0),
+                        Token::new("Name", (7, 11), 0),
+                        Token::new("HTTPServer", (12, 22), 1),
+                        Token::new("HTTP", (12, 16), 1),
+                        Token::new("Server", (16, 22), 1),
+                        Token::new("snake_case_id", (23, 36), 2),
+                        Token::new("snake", (23, 28), 2),
+                        Token::new("case", (29, 33), 2),
+                        Token::new("id", (34, 36), 2),
+                        Token::new("plain", (37, 42), 3)]);
+        let tokens = collect(TokenizerChain::from_bytes("getUserName")
+                                 .word_split()
+                                 .ngrams()
+                                 .lowercase()
+                                 .build());
+        for &(word, offsets) in &[("get", (0, 3)), ("user", (3, 7)), ("name", (7, 11))] {
+            assert!(tokens.contains(&Token::new(word, offsets, 0)));
+        }
+    }
+
+    #[test]
+    fn ascii_lowercase() {
+        let tokenizer = LowercaseFilter::with_locale(EnglishUtf8::from_bytes("ÄPFEL and Äpfel"),
+                                                     Locale::Ascii);
+        assert_eq!(collect(tokenizer),
+                   vec![Token::new("Äpfel", (0, 6), 0),
+                        Token::new("and", (7, 10), 1),
+                        Token::new("Äpfel", (11, 17), 2)]);
+        assert_eq!(Locale::Ascii.to_lowercase("ÀB"), "Àb");
+    }
 }
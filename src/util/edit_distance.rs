@@ -0,0 +1,60 @@
+use std::cmp;
+use std::mem;
+
+/// Returns the Levenshtein distance between the strings: the least number of char insertions,
+/// deletions and substitutions that turn one into the other.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    edit_distance(a, b, false)
+}
+
+/// Returns the Damerau-Levenshtein distance between the strings, which is like the Levenshtein
+/// distance except that swapping two adjacent chars also counts as a single edit. This is the
+/// optimal string alignment variant, in which no substring is edited more than once.
+pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
+    edit_distance(a, b, true)
+}
+
+fn edit_distance(a: &str, b: &str, transpositions: bool) -> usize {
+    let a: Vec<_> = a.chars().collect();
+    let b: Vec<_> = b.chars().collect();
+    // The distances from the prefixes of `a` to the prefixes of `b` of the current length and the
+    // two lengths before it.
+    let mut before_previous = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..a.len() + 1 {
+        current[0] = i;
+        for j in 1..b.len() + 1 {
+            let substitution_cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = cmp::min(cmp::min(previous[j] + 1, current[j - 1] + 1),
+                                  previous[j - 1] + substitution_cost);
+            if transpositions && i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = cmp::min(current[j], before_previous[j - 2] + 1);
+            }
+        }
+        mem::swap(&mut before_previous, &mut previous);
+        mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[test]
+fn test_levenshtein() {
+    assert_eq!(levenshtein("", ""), 0);
+    assert_eq!(levenshtein("rust", ""), 4);
+    assert_eq!(levenshtein("", "rust"), 4);
+    assert_eq!(levenshtein("kitten", "sitting"), 3);
+    assert_eq!(levenshtein("teh", "the"), 2);
+    assert_eq!(levenshtein("日本語", "日本"), 1);
+}
+
+#[test]
+fn test_damerau_levenshtein() {
+    assert_eq!(damerau_levenshtein("", ""), 0);
+    assert_eq!(damerau_levenshtein("kitten", "sitting"), 3);
+    assert_eq!(damerau_levenshtein("teh", "the"), 1);
+    assert_eq!(damerau_levenshtein("recieve", "receive"), 1);
+    assert_eq!(damerau_levenshtein("ab", "ba"), 1);
+    // Optimal string alignment doesn't edit the transposed chars again
+    assert_eq!(damerau_levenshtein("ca", "abc"), 3);
+}
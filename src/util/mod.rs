@@ -2,6 +2,8 @@
 pub mod btree_map_ext;
 /// Contains the Coalesce trait, for performing coalescence on collections and its items.
 pub mod coalesce;
+/// Contains functions computing the edit distance between strings, e.g. for fuzzy matching.
+pub mod edit_distance;
 /// Contains the Successor trait, which is the same thing as `std::iter::Step`, except it's
 /// implemented for chars.
 pub mod successor;
@@ -11,4 +13,5 @@ pub mod char_utf8;
 
 pub use self::btree_map_ext::{BTreeMapExt, Intersection};
 pub use self::coalesce::{Coalesce, Merge, MergeCoalesceMap};
+pub use self::edit_distance::{damerau_levenshtein, levenshtein};
 pub use self::successor::Successor;